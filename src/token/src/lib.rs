@@ -2,21 +2,33 @@ mod permissions;
 mod ports;
 mod state;
 mod validations;
-use crate::state::escrow::SaleStatus;
+use crate::state::escrow::{Booking, EscrowReconciliation, PendingRefund, SaleStatus, SaleSummary};
+use crate::state::settlement::Settlement;
+use crate::state::excess_refund::ExcessRefund;
+use crate::state::marketplace::{Listing, Purchase};
+use crate::state::ledger::LedgerInfo;
 use crate::state::icrc7::ICRC7MetadataQueryResult;
 use crate::state::metadata::*;
 use crate::state::supported_standards::SupportedStandard;
+use crate::state::approvals::*;
+use crate::state::settings::{CollectionSettings, UpdateCollectionSettingsArgs};
+use crate::state::distribution::{DistributionRound, Payout, QueuedPayout};
+use crate::state::dividends::PendingClaim;
+use crate::state::compliance::{AllowlistEntry, InvestorLimits, InvestorPolicy, UpdateAllowlistArgs, UpdateInvestorPolicyArgs};
 use candid::Nat;
 use candid::Principal;
-use ic_cdk::storage;
 use ic_cdk_macros::*;
 use state::metadata::Metadata;
 use state::models::*;
+use state::memory;
+use state::migration;
 use state::MetaDataState;
 use state::State;
 use std::cell::RefCell;
 use icrc_ledger_types::icrc1::transfer::BlockIndex;
 use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc3::archive::{GetArchivesArgs, ICRC3ArchiveInfo};
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType};
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(Default::default());
@@ -32,42 +44,37 @@ fn init(base: CanisterArgs) {
 
 fn init_hook(meta: Metadata) {
     STATE.with_borrow_mut(|state| {
-        *state = State {
-            metadata: Some(MetaDataState {
-                metadata: meta,
-                total_supply: 0,
-            }),
-            ..Default::default()
-        };
+        state.set_metadata(MetaDataState {
+            metadata: meta,
+            total_supply: 0,
+        });
     });
+    state::sale::schedule_sale_end();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    STATE.with(|state| {
-        storage::stable_save((State {
-            metadata: state.borrow().metadata.clone(),
-            escrow: state.borrow().escrow.clone(),
-            transactions: state.borrow().transactions.clone(),
-            tokens: state.borrow().tokens.clone(),
-        },))
-        .unwrap()
-    });
+    STATE.with_borrow(memory::save_upgrade_state).unwrap_or_else(|e| ic_cdk::trap(&e));
 }
 
 #[post_upgrade]
 fn post_upgrade(upgrade: CanisterArgs) {
-    let state: Result<(State,), String> = storage::stable_restore();
-    match state {
-        Ok(state) => {
-            STATE.with(|s| {
-                *s.borrow_mut() = state.0;
-            });
-        }
-        Err(e) => {
-            println!("Failed to do post upgrade {e}");
-        }
-    }
+    // Canisters installed before the memory manager hold the whole state as a
+    // `stable_save` blob, which has to be read before the stable structures take over.
+    let state = if memory::has_legacy_state() {
+        migration::restore_legacy_state()
+    } else {
+        memory::load_upgrade_state::<State>()
+    };
+
+    // Trapping rolls the upgrade back instead of leaving an empty collection.
+    let state = state.unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to do post upgrade {e}")));
+    STATE.with(|s| {
+        *s.borrow_mut() = state;
+        // Certified data does not survive an upgrade.
+        s.borrow().transactions.certify();
+    });
+    state::sale::schedule_sale_end();
 }
 
 ic_cdk_macros::export_candid!();
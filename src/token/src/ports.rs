@@ -1,10 +1,21 @@
 use candid::{Nat, Principal};
 use ic_cdk::caller;
+use crate::state::approvals::*;
 use crate::state::metadata::UpdateMetadataArgs;
+use crate::state::settings::{CollectionSettings, UpdateCollectionSettingsArgs};
 use crate::state::subaccount::Subaccount;
-use crate::validations::{check_collection_owner,check_not_anonymous};
-use crate::{BookTokensArg, Icrc7BalanceOfArgItem, Icrc7OwnerOfRetItemInner, Icrc7TokenMetadataRetItemInnerItem1, Icrc7TokensOfArg, Icrc7TransferArgItem, Icrc7TransferRetItemInner};
-use crate::{state::{escrow::SaleStatus, models::{GetEscrowAccountRet, GetMetadataRet}}, STATE};
+use crate::state::account::Account;
+use crate::state::distribution::{self, DistributionRound, Payout, QueuedPayout};
+use crate::state::dividends::{self, PendingClaim};
+use crate::state::sale;
+use crate::state::ledger::{self, LedgerInfo};
+use crate::state::settlement::{self, Settlement};
+use crate::state::excess_refund::{self, ExcessRefund};
+use crate::state::marketplace::{Listing, Purchase};
+use crate::state::compliance::{AllowlistEntry, InvestorLimits, InvestorPolicy, UpdateAllowlistArgs, UpdateInvestorPolicyArgs};
+use crate::validations::{check_collection_owner,check_collection_owner_or_compliance,check_collection_owner_or_treasury,check_not_anonymous,check_subaccount};
+use crate::{BookTokensArg, Icrc1Account, Icrc7BalanceOfArgItem, Icrc7BurnArgItem, Icrc7BurnRetItemInner, Icrc7OwnerOfRetItemInner, Icrc7TokenMetadataRetItemInnerItem1, Icrc7TokensOfArg, Icrc7TransferArgItem, Icrc7TransferRetItemInner};
+use crate::{state::{escrow::{Booking, EscrowReconciliation, PendingRefund, SaleStatus, SaleSummary}, models::{GetEscrowAccountRet, GetMetadataRet}}, STATE};
 use ic_cdk_macros::*;
 
 
@@ -15,26 +26,123 @@ pub async fn change_ownership( arg0: Principal) -> Result<Nat, String> {
 }
 #[update(guard = "check_collection_owner")]
 pub async fn update_metadata( arg0: UpdateMetadataArgs) -> Result<Nat, String> {
-    STATE.with_borrow_mut( |f|{  f.metadata.as_mut().map(|f| f.metadata.update(arg0)); Ok(f.transactions.index().clone()) } )
+    let (index, currencies) = STATE.with_borrow_mut( |f|{
+        let mut state = f.metadata().ok_or("Metadata not set".to_string())?;
+        let before = state.metadata.clone();
+        state.metadata.update(arg0)?;
+        if f.escrow.total_booked_tokens > 0 {
+            state.metadata.check_sale_terms_kept(&before)?;
+        }
+        let currencies = state.metadata.currencies();
+        f.set_metadata(state);
+        Ok::<_, String>((f.transactions.index(), currencies))
+    } )?;
+    sale::schedule_sale_end();
+    // A ledger that cannot be reached keeps what was cached for it, and is read on first use if nothing was.
+    for currency in currencies {
+        let _ = ledger::refresh(currency.ledger).await;
+    }
+    Ok(index)
 }
 
+/// The cached info of every ledger the sale accepts.
+#[query]
+pub fn get_ledger_info() -> Vec<LedgerInfo> {
+    STATE.with( |f|{
+        let f = f.borrow();
+        let currencies = f.metadata().map(|state| state.metadata.currencies()).unwrap_or_default();
+        currencies.iter().filter_map(|currency| f.ledger_info.get(&currency.ledger).cloned()).collect()
+    } )
+}
 
-// #[update(guard = "check_not_anonymous")]
-#[update]
+
+#[update(guard = "check_not_anonymous")]
 pub async fn book_tokens( arg: BookTokensArg) -> Result<bool, String> {
     let   f  =  STATE.with_borrow( |f|  f.clone() );
-    let qunatity =  arg.quantity.clone();
-    let res = f.book_tokens(arg).await?;
+    f.book_tokens(arg).await
+}
+
+#[update(guard = "check_not_anonymous")]
+pub async fn cancel_booking( quantity: u128, ledger: Option<Principal>) -> Result<Booking, String> {
+    let   f  =  STATE.with_borrow( |f|  f.clone() );
+    f.cancel_booking(quantity, ledger).await
+}
+
+#[update(guard = "check_not_anonymous")]
+pub async fn retry_booking_refund( booking_id: u64) -> Result<Booking, String> {
+    let   f  =  STATE.with_borrow( |f|  f.clone() );
+    f.retry_booking_refund(booking_id).await
+}
+
+#[query]
+pub fn get_bookings( arg0: Option<Principal>) -> Vec<Booking> {
+    STATE.with( |f|  f.borrow().escrow.bookings_of(&arg0.unwrap_or(caller())) )
+}
+
+/// Refunds of cancelled bookings the ledger has not confirmed yet, by booking id.
+#[query]
+pub fn get_pending_refunds( arg0: Option<Principal>) -> Vec<(u64, PendingRefund)> {
+    let investor = arg0.unwrap_or(caller());
+    STATE.with( |f|  f.borrow().escrow.pending_refunds.iter().filter(|(_, refund)| refund.investor == investor).map(|(id, refund)| (*id, refund.clone())).collect() )
+}
 
-    STATE.with(|f|{  f.borrow_mut().escrow.book_tokens(caller(), qunatity.into());  } );
-    Ok(res)
+#[update(guard = "check_not_anonymous")]
+pub fn set_refund_account( account: Icrc1Account) -> Result<(), String> {
+    STATE.with( |f|  f.borrow_mut().escrow.set_refund_account(caller(), account) )
 }
 
 #[query]
+pub fn get_refund_account( arg0: Option<Principal>) -> Icrc1Account {
+    STATE.with( |f|  f.borrow().escrow.refund_account(&arg0.unwrap_or(caller())) )
+}
+
+#[update(guard = "check_collection_owner")]
+pub fn add_compliance_officer( officer: Principal) -> bool {
+    STATE.with( |f|  f.borrow_mut().compliance.compliance_officers.insert(officer) )
+}
+
+#[update(guard = "check_collection_owner")]
+pub fn remove_compliance_officer( officer: Principal) -> bool {
+    STATE.with( |f|  f.borrow_mut().compliance.compliance_officers.remove(&officer) )
+}
+
+#[update(guard = "check_collection_owner_or_compliance")]
+pub fn update_investor_policy( args: UpdateInvestorPolicyArgs) -> Result<InvestorPolicy, String> {
+    STATE.with( |f|{  let compliance = &mut f.borrow_mut().compliance; compliance.update_policy(args)?; Ok(compliance.policy()) } )
+}
+
+#[update(guard = "check_collection_owner_or_compliance")]
+pub fn update_allowlist( args: UpdateAllowlistArgs) -> Result<bool, String> {
+    STATE.with( |f|  f.borrow_mut().compliance.update_allowlist(args) )?;
+    Ok(true)
+}
+
+#[query]
+pub fn get_investor_policy() -> InvestorPolicy {
+    STATE.with( |f|  f.borrow().compliance.policy() )
+}
+
+#[query]
+pub fn get_allowlist( prev: Option<Principal>, take: Option<u32>) -> Vec<AllowlistEntry> {
+    STATE.with( |f|{  let f = f.borrow(); f.compliance.allowlist(prev, f.settings.take(take)) } )
+}
+
+#[query]
+pub fn get_investor_limits( investor: Principal) -> Result<InvestorLimits, String> {
+    STATE.with( |f|  f.borrow().compliance.limits_of(&investor) )
+}
+
+#[update(guard = "check_collection_owner_or_treasury")]
 pub async fn get_excess_escrow_balance() -> Result<Vec<Principal>, String> {
   STATE.with_borrow( |f|  f.clone() ).get_excess_escrow_balance().await
 }
 
+/// Open to the collection owner and the treasury, which checks escrow before `accept_sale`.
+#[update(guard = "check_collection_owner_or_treasury")]
+pub async fn reconcile_escrow() -> Result<EscrowReconciliation, String> {
+  STATE.with_borrow( |f|  f.clone() ).reconcile_escrow().await
+}
+
 
 #[update(guard = "check_collection_owner")]
 pub async fn accept_sale() -> Result<bool, String> {
@@ -42,6 +150,17 @@ pub async fn accept_sale() -> Result<bool, String> {
     state.accept_sale().await 
 }
 
+#[query]
+pub fn get_settlements( prev: Option<(Principal, Principal)>, take: Option<u32>) -> Vec<Settlement> {
+    STATE.with( |f|  settlement::settlements(prev, f.borrow().settings.take(take)) )
+}
+
+/// Settles a payment `accept_sale` sent without learning whether it was made, as found on the ledger.
+#[update(guard = "check_collection_owner")]
+pub fn resolve_settlement( investor: Principal, ledger: Principal, block_index: Option<Nat>) -> Result<Settlement, String> {
+    settlement::resolve_settlement(&(investor, ledger), block_index, ic_cdk::api::time())
+}
+
 #[update(guard = "check_collection_owner")]
 pub async fn reject_sale() -> Result<bool, String> {
     let    f  =  STATE.with( |f|  f.borrow().clone() );
@@ -60,6 +179,90 @@ pub async fn refund_excess_after_sale(invester: Principal) -> Result<bool, Strin
     f.refund_excess_after_sale(invester).await
 }
 
+#[update(guard = "check_collection_owner")]
+pub async fn refund_all_excess() -> Result<Vec<ExcessRefund>, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.refund_all_excess().await
+}
+
+#[query]
+pub fn get_excess_refunds( prev: Option<(Principal, Principal)>, take: Option<u32>) -> Vec<ExcessRefund> {
+    STATE.with( |f|  excess_refund::excess_refunds(prev, f.borrow().settings.take(take)) )
+}
+
+#[query]
+pub fn get_distribution_account() -> Icrc1Account {
+    distribution::distribution_account()
+}
+
+#[update(guard = "check_collection_owner_or_treasury")]
+pub async fn distribute_revenue() -> Result<DistributionRound, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.distribute_revenue().await
+}
+
+#[update(guard = "check_collection_owner_or_treasury")]
+pub async fn retry_failed_payouts() -> Result<Vec<QueuedPayout>, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.retry_failed_payouts().await
+}
+
+#[query]
+pub fn get_distribution_round( round_id: u64) -> Option<DistributionRound> {
+    STATE.with( |f|  f.borrow().distribution.get_round(round_id) )
+}
+
+#[query]
+pub fn get_distribution_rounds( prev: Option<u64>, take: Option<u32>) -> Vec<DistributionRound> {
+    STATE.with( |f|{  let f = f.borrow(); f.distribution.rounds(prev, f.settings.take(take)) } )
+}
+
+#[query]
+pub fn get_failed_payouts() -> Vec<QueuedPayout> {
+    STATE.with( |f|  f.borrow().distribution.queued() )
+}
+
+/// Settles a payout whose transfer did not return, as found on the ledger.
+#[update(guard = "check_collection_owner")]
+pub fn resolve_payout( round_id: u64, index: u32, block_index: Option<Nat>) -> Result<Payout, String> {
+    STATE.with( |f|  f.borrow_mut().distribution.resolve_payout(round_id, index, block_index, ic_cdk::api::time()) )
+}
+
+#[query]
+pub fn get_dividend_account() -> Icrc1Account {
+    dividends::dividend_account()
+}
+
+#[update(guard = "check_collection_owner_or_treasury")]
+pub async fn accrue_revenue() -> Result<u128, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.accrue_revenue().await
+}
+
+#[query]
+pub fn claimable( account: Icrc1Account) -> u128 {
+    check_subaccount(&account.subaccount).unwrap_or_else(|e| ic_cdk::trap(&e));
+    STATE.with( |f|{  let tokens = &f.borrow().tokens; let account = Account::from(&account); tokens.dividends.claimable(&account, tokens.balance_of(&account)) } )
+}
+
+#[query]
+pub fn get_pending_claims() -> Vec<(Icrc1Account, PendingClaim)> {
+    STATE.with( |f|  f.borrow().tokens.dividends.pending_claims() )
+}
+
+/// Settles a claim whose transfer did not return, as found on the ledger.
+#[update(guard = "check_collection_owner")]
+pub fn resolve_claim( account: Icrc1Account, block_index: Option<Nat>) -> Result<PendingClaim, String> {
+    check_subaccount(&account.subaccount)?;
+    STATE.with( |f|  f.borrow_mut().tokens.dividends.resolve_claim(&Account::from(&account), block_index, ic_cdk::api::time()) )
+}
+
+#[update(guard = "check_not_anonymous")]
+pub async fn claim( subaccount: Option<Vec<u8>>) -> Result<Nat, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.claim(subaccount).await
+}
+
 #[query]
 pub async fn get_booked_tokens( arg0: Option<Principal>) -> u128 {
     STATE.with( |f|  f.borrow().clone() )
@@ -71,6 +274,107 @@ pub fn icrc7_transfer( args: Vec<Icrc7TransferArgItem>) -> Vec<Option<Icrc7Trans
     STATE.with( |f|  f.borrow_mut().icrc_7_transfer(args) )
 }
 
+#[update(guard = "check_not_anonymous")]
+pub fn list_token( token_id: u32, price: u128, ledger: Principal) -> Result<Listing, String> {
+    STATE.with( |f|  f.borrow_mut().list_token(token_id, price, ledger) )
+}
+
+#[update(guard = "check_not_anonymous")]
+pub fn delist( token_id: u32) -> Result<Listing, String> {
+    STATE.with( |f|  f.borrow_mut().delist(token_id) )
+}
+
+#[update(guard = "check_not_anonymous")]
+pub async fn buy( token_id: u32) -> Result<Nat, String> {
+    let    f  =  STATE.with( |f|  f.borrow().clone() );
+    f.buy(token_id).await
+}
+
+#[query]
+pub fn get_listings( prev: Option<u32>, take: Option<u32>) -> Vec<Listing> {
+    STATE.with( |f|{  let f = f.borrow(); f.marketplace.listings(prev, f.settings.take(take)) } )
+}
+
+#[query]
+pub fn get_purchases() -> Vec<(u32, Purchase)> {
+    STATE.with( |f|  f.borrow().marketplace.purchases() )
+}
+
+/// Settles a payment for a listing that `buy` sent without learning whether it was made, as found on the ledger.
+#[update(guard = "check_collection_owner")]
+pub fn resolve_purchase( token_id: u32, block_index: Option<Nat>) -> Result<Purchase, String> {
+    STATE.with( |f|  f.borrow_mut().resolve_purchase(token_id, block_index) )
+}
+
+#[update]
+pub fn icrc7_burn( args: Vec<Icrc7BurnArgItem>) -> Vec<Option<Icrc7BurnRetItemInner>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_7_burn(args) )
+}
+
+#[update(guard = "check_collection_owner")]
+pub fn update_collection_settings( args: UpdateCollectionSettingsArgs) -> Result<CollectionSettings, String> {
+    STATE.with( |f|{  let settings = &mut f.borrow_mut().settings; settings.update(args)?; Ok(settings.clone()) } )
+}
+
+#[query]
+pub fn get_collection_settings() -> CollectionSettings {
+    STATE.with( |f|  f.borrow().settings.clone() )
+}
+
+#[update]
+pub fn icrc37_approve_tokens( args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_37_approve_tokens(args) )
+}
+
+#[update]
+pub fn icrc37_approve_collection( args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_37_approve_collection(args) )
+}
+
+#[update]
+pub fn icrc37_revoke_token_approvals( args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<RevokeTokenApprovalResult>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_37_revoke_token_approvals(args) )
+}
+
+#[update]
+pub fn icrc37_revoke_collection_approvals( args: Vec<RevokeCollectionApprovalArg>) -> Vec<Option<RevokeCollectionApprovalResult>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_37_revoke_collection_approvals(args) )
+}
+
+#[update]
+pub fn icrc37_transfer_from( args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>>  {
+    STATE.with( |f|  f.borrow_mut().icrc_37_transfer_from(args) )
+}
+
+#[query]
+pub fn icrc37_is_approved( args: Vec<IsApprovedArg>) -> Vec<bool>  {
+    STATE.with( |f|  f.borrow().icrc_37_is_approved(args) )
+}
+
+#[query]
+pub fn icrc37_get_token_approvals( token_id: u32,
+    prev: Option<TokenApproval>,
+    take: Option<u32>,) -> Vec<TokenApproval>  {
+    STATE.with( |f|  f.borrow().icrc_37_get_token_approvals(token_id, prev, take) )
+}
+
+#[query]
+pub fn icrc37_get_collection_approvals( owner: Icrc1Account,
+    prev: Option<ApprovalInfo>,
+    take: Option<u32>,) -> Vec<ApprovalInfo>  {
+    STATE.with( |f|  f.borrow().icrc_37_get_collection_approvals(owner, prev, take) )
+}
+
+#[query]
+pub fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION.into())
+}
+
+#[query]
+pub fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(MAX_REVOKE_APPROVALS.into())
+}
+
 #[query]
 pub fn icrc7_balance_of( args: Vec<Icrc7BalanceOfArgItem>) -> Vec<u64>  {
     STATE.with( |f|  f.borrow().icrc_7_balance_of(args) )
@@ -85,6 +389,11 @@ pub fn icrc7_token_metadata( args: Vec<u32>) -> Vec<Option<Vec<(String, Icrc7Tok
     STATE.with( |f|  f.borrow().icrc_7_token_metadata(args) )
 }
 
+#[update(guard = "check_collection_owner")]
+pub fn extend_token_metadata( token_ids: Vec<u32>, entries: Vec<(String, Icrc7TokenMetadataRetItemInnerItem1)>) -> Result<bool, String> {
+    STATE.with( |f|  f.borrow_mut().extend_token_metadata(token_ids, entries) )
+}
+
 #[query]
 pub fn icrc7_tokens(  prev: Option<u32>,
     take: Option<u32>,) -> Vec<u32>  {
@@ -117,6 +426,11 @@ pub async fn get_participating_investors() -> Vec<Principal> {
 }
 
 
+#[query]
+pub fn get_sale_summary() -> Result<SaleSummary, String> {
+    STATE.with( |f|  f.borrow().get_sale_summary() )
+}
+
 #[query]
 pub async fn get_sale_status() -> SaleStatus {
     STATE.with( |f|  f.borrow().clone() )
@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::Serialize;
+
+use super::models::Icrc1Account;
+use super::Owner;
+
+const MAX_PRINCIPAL_LEN: u32 = 29;
+
+/// An ICRC-1 account in canonical form, used as a map key.
+/// The default subaccount is always stored as 32 zero bytes, so an account given
+/// with `None` and one given with `Some([0; 32])` are the same key.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: [u8; 32],
+}
+
+impl Account {
+    /// The endpoints reject subaccounts that are not 32 bytes long, so any other length is a bug.
+    pub fn new(owner: Principal, subaccount: &Option<Vec<u8>>) -> Self {
+        let subaccount = match subaccount {
+            Some(subaccount) => subaccount.as_slice().try_into().expect("Subaccounts must be 32 bytes long."),
+            None => [0; 32],
+        };
+        Self { owner, subaccount }
+    }
+}
+
+impl From<&Owner> for Account {
+    fn from(owner: &Owner) -> Self {
+        Self::new(owner.principal, &owner.subaccount)
+    }
+}
+
+impl From<&Icrc1Account> for Account {
+    fn from(account: &Icrc1Account) -> Self {
+        Self::new(account.owner, &account.subaccount)
+    }
+}
+
+/// The default subaccount is given back as `None`.
+impl From<&Account> for Icrc1Account {
+    fn from(account: &Account) -> Self {
+        Self {
+            owner: account.owner,
+            subaccount: (account.subaccount != [0; 32]).then(|| account.subaccount.to_vec()),
+        }
+    }
+}
+
+impl Storable for Account {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.subaccount.to_vec();
+        bytes.extend_from_slice(self.owner.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (subaccount, owner) = bytes.split_at(32);
+        Self {
+            owner: Principal::from_slice(owner),
+            subaccount: subaccount.try_into().expect("Invalid subaccount"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + MAX_PRINCIPAL_LEN,
+        is_fixed_size: false,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_subaccount_is_canonical() {
+        let owner = Principal::from_slice(&[1]);
+        assert_eq!(Account::new(owner, &None), Account::new(owner, &Some(vec![0; 32])));
+        assert_ne!(Account::new(owner, &None), Account::new(owner, &Some(vec![1; 32])));
+
+        let account = Account::new(owner, &Some(vec![7; 32]));
+        assert_eq!(Account::from_bytes(account.to_bytes()), account);
+
+        // Short subaccounts are not padded into another account.
+        assert!(std::panic::catch_unwind(|| Account::new(owner, &Some(vec![1]))).is_err());
+        assert!(std::panic::catch_unwind(|| Account::new(owner, &Some(vec![]))).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Nat};
+use serde::Serialize;
+
+use super::{account::Account, models::Icrc1Account, Owner};
+
+pub const MAX_APPROVALS_PER_TOKEN_OR_COLLECTION: usize = 10;
+pub const MAX_REVOKE_APPROVALS: usize = 10;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Icrc1Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: u64,
+}
+
+impl ApprovalInfo {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// ICRC-37 approvals, kept next to `TokenState`.
+/// Approvals are keyed by the spender's canonical `Account`.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct ApprovalStore {
+    /// token id -> spender -> approval
+    pub token_approvals: BTreeMap<u32, BTreeMap<Account, ApprovalInfo>>,
+    /// owner account -> spender -> approval
+    pub collection_approvals: BTreeMap<Account, BTreeMap<Account, ApprovalInfo>>,
+}
+
+fn insert_approval(
+    approvals: &mut BTreeMap<Account, ApprovalInfo>,
+    approval: ApprovalInfo,
+    now: u64,
+) -> Result<(), String> {
+    approvals.retain(|_, approval| approval.is_active(now));
+
+    let spender = Account::from(&approval.spender);
+    if !approvals.contains_key(&spender) && approvals.len() >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+        return Err(format!(
+            "At most {MAX_APPROVALS_PER_TOKEN_OR_COLLECTION} approvals are allowed."
+        ));
+    }
+    approvals.insert(spender, approval);
+    Ok(())
+}
+
+/// Removes the approval for `spender`, or every approval when `spender` is `None`.
+/// Returns whether anything was removed.
+fn remove_approvals(
+    approvals: Option<&mut BTreeMap<Account, ApprovalInfo>>,
+    spender: Option<&Icrc1Account>,
+) -> bool {
+    let Some(approvals) = approvals else {
+        return false;
+    };
+    match spender {
+        Some(spender) => approvals.remove(&Account::from(spender)).is_some(),
+        None => {
+            let removed = !approvals.is_empty();
+            approvals.clear();
+            removed
+        }
+    }
+}
+
+/// Lists active approvals ordered by spender, starting after `prev`.
+fn page(
+    approvals: Option<&BTreeMap<Account, ApprovalInfo>>,
+    prev: Option<&Icrc1Account>,
+    take: Option<u32>,
+    now: u64,
+) -> Vec<ApprovalInfo> {
+    let Some(approvals) = approvals else {
+        return vec![];
+    };
+    let take = take.map_or(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION, |take| take as usize);
+    let prev = prev.map(Account::from);
+
+    approvals
+        .iter()
+        .filter(|(spender, _)| prev.as_ref().is_none_or(|prev| *spender > prev))
+        .map(|(_, approval)| approval)
+        .filter(|approval| approval.is_active(now))
+        .take(take)
+        .cloned()
+        .collect()
+}
+
+impl ApprovalStore {
+    pub fn approve_token(&mut self, token_id: u32, approval: ApprovalInfo, now: u64) -> Result<(), String> {
+        insert_approval(self.token_approvals.entry(token_id).or_default(), approval, now)
+    }
+
+    pub fn approve_collection(&mut self, owner: &Owner, approval: ApprovalInfo, now: u64) -> Result<(), String> {
+        insert_approval(self.collection_approvals.entry(Account::from(owner)).or_default(), approval, now)
+    }
+
+    pub fn revoke_token(&mut self, token_id: u32, spender: Option<&Icrc1Account>) -> bool {
+        let removed = remove_approvals(self.token_approvals.get_mut(&token_id), spender);
+        if self.token_approvals.get(&token_id).is_some_and(BTreeMap::is_empty) {
+            self.token_approvals.remove(&token_id);
+        }
+        removed
+    }
+
+    pub fn revoke_collection(&mut self, owner: &Owner, spender: Option<&Icrc1Account>) -> bool {
+        let key = Account::from(owner);
+        let removed = remove_approvals(self.collection_approvals.get_mut(&key), spender);
+        if self.collection_approvals.get(&key).is_some_and(BTreeMap::is_empty) {
+            self.collection_approvals.remove(&key);
+        }
+        removed
+    }
+
+    /// Token approvals do not survive a change of owner.
+    pub fn clear_token(&mut self, token_id: u32) {
+        self.token_approvals.remove(&token_id);
+    }
+
+    /// Whether `spender` may move `token_id` on behalf of its `owner`,
+    /// either through a token approval or a collection approval.
+    pub fn is_approved(&self, token_id: u32, owner: &Owner, spender: &Icrc1Account, now: u64) -> bool {
+        let spender = Account::from(spender);
+        let token_approved = self
+            .token_approvals
+            .get(&token_id)
+            .and_then(|approvals| approvals.get(&spender))
+            .is_some_and(|approval| approval.is_active(now));
+        let collection_approved = self
+            .collection_approvals
+            .get(&Account::from(owner))
+            .and_then(|approvals| approvals.get(&spender))
+            .is_some_and(|approval| approval.is_active(now));
+
+        token_approved || collection_approved
+    }
+
+    pub fn token_approvals(&self, token_id: u32, prev: Option<&Icrc1Account>, take: Option<u32>, now: u64) -> Vec<ApprovalInfo> {
+        page(self.token_approvals.get(&token_id), prev, take, now)
+    }
+
+    pub fn collection_approvals(&self, owner: &Owner, prev: Option<&Icrc1Account>, take: Option<u32>, now: u64) -> Vec<ApprovalInfo> {
+        page(self.collection_approvals.get(&Account::from(owner)), prev, take, now)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApproveTokenArg {
+    pub token_id: u32,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ApproveTokenResult {
+    Ok(Nat),
+    Err(ApproveTokenError),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ApproveCollectionResult {
+    Ok(Nat),
+    Err(ApproveCollectionError),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<Icrc1Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: u32,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RevokeTokenApprovalResult {
+    Ok(Nat),
+    Err(RevokeTokenApprovalError),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<Icrc1Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RevokeCollectionApprovalResult {
+    Ok(Nat),
+    Err(RevokeCollectionApprovalError),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IsApprovedArg {
+    pub spender: Icrc1Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenApproval {
+    pub token_id: u32,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Icrc1Account,
+    pub to: Icrc1Account,
+    pub token_id: u32,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TransferFromResult {
+    Ok(Nat),
+    Err(TransferFromError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn account(id: u8) -> Icrc1Account {
+        Icrc1Account {
+            owner: Principal::from_slice(&[id]),
+            subaccount: None,
+        }
+    }
+
+    fn approval(spender: u8, expires_at: Option<u64>) -> ApprovalInfo {
+        ApprovalInfo {
+            spender: account(spender),
+            from_subaccount: None,
+            expires_at,
+            memo: None,
+            created_at_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_approvals_expire_and_clear_on_transfer() {
+        let owner = Owner { principal: Principal::from_slice(&[1]), subaccount: None };
+        let mut store = ApprovalStore::default();
+
+        store.approve_token(7, approval(2, Some(100)), 10).unwrap();
+        store.approve_collection(&owner, approval(3, None), 10).unwrap();
+
+        assert!(store.is_approved(7, &owner, &account(2), 50));
+        assert!(!store.is_approved(7, &owner, &account(2), 100));
+        assert!(store.is_approved(8, &owner, &account(3), 100));
+
+        store.clear_token(7);
+        assert!(!store.is_approved(7, &owner, &account(2), 50));
+        assert!(store.is_approved(7, &owner, &account(3), 50));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use candid::{CandidType, Deserialize, Principal};
+
+/// Booking limits, counted over everything an investor has booked in the sale.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct InvestorLimits {
+    pub min_tokens: Option<u128>,
+    pub max_tokens: Option<u128>,
+}
+
+impl InvestorLimits {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min_tokens, self.max_tokens) {
+            if min > max {
+                return Err("min_tokens must not exceed max_tokens.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fields left unset here fall back to `default`.
+    fn or(&self, default: &InvestorLimits) -> InvestorLimits {
+        InvestorLimits {
+            min_tokens: self.min_tokens.or(default.min_tokens),
+            max_tokens: self.max_tokens.or(default.max_tokens),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowlistEntry {
+    pub investor: Principal,
+    /// Overrides the default limits for this investor.
+    pub limits: InvestorLimits,
+}
+
+/// Who may book tokens, and how many. Compliance officers manage it next to the collection owner.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct ComplianceStore {
+    pub compliance_officers: BTreeSet<Principal>,
+    /// Only investors on the allowlist may book while this is set.
+    pub allowlist_enabled: bool,
+    pub allowlist: BTreeMap<Principal, InvestorLimits>,
+    pub default_limits: InvestorLimits,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvestorPolicy {
+    pub compliance_officers: Vec<Principal>,
+    pub allowlist_enabled: bool,
+    pub default_limits: InvestorLimits,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateInvestorPolicyArgs {
+    pub allowlist_enabled: Option<bool>,
+    pub default_limits: Option<InvestorLimits>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateAllowlistArgs {
+    /// Added, or updated if already listed.
+    pub add: Vec<AllowlistEntry>,
+    pub remove: Vec<Principal>,
+}
+
+impl ComplianceStore {
+    pub fn is_compliance_officer(&self, principal: &Principal) -> bool {
+        self.compliance_officers.contains(principal)
+    }
+
+    pub fn policy(&self) -> InvestorPolicy {
+        InvestorPolicy {
+            compliance_officers: self.compliance_officers.iter().cloned().collect(),
+            allowlist_enabled: self.allowlist_enabled,
+            default_limits: self.default_limits,
+        }
+    }
+
+    pub fn update_policy(&mut self, args: UpdateInvestorPolicyArgs) -> Result<(), String> {
+        if let Some(default_limits) = args.default_limits {
+            default_limits.validate()?;
+            self.default_limits = default_limits;
+        }
+        if let Some(allowlist_enabled) = args.allowlist_enabled {
+            self.allowlist_enabled = allowlist_enabled;
+        }
+        Ok(())
+    }
+
+    /// Applies removals after additions; nothing changes if any entry is invalid.
+    pub fn update_allowlist(&mut self, args: UpdateAllowlistArgs) -> Result<(), String> {
+        if let Some(entry) = args.add.iter().find(|entry| entry.limits.validate().is_err()) {
+            return Err(format!("Invalid limits for {}: min_tokens must not exceed max_tokens.", entry.investor));
+        }
+        for entry in args.add {
+            self.allowlist.insert(entry.investor, entry.limits);
+        }
+        for investor in args.remove {
+            self.allowlist.remove(&investor);
+        }
+        Ok(())
+    }
+
+    /// Up to `take` allowlist entries ordered by principal, starting after `prev`.
+    pub fn allowlist(&self, prev: Option<Principal>, take: usize) -> Vec<AllowlistEntry> {
+        let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+        self.allowlist
+            .range((start, Bound::Unbounded))
+            .take(take)
+            .map(|(investor, limits)| AllowlistEntry {
+                investor: *investor,
+                limits: *limits,
+            })
+            .collect()
+    }
+
+    /// The limits that apply to `investor`, or an error if they may not book at all.
+    pub fn limits_of(&self, investor: &Principal) -> Result<InvestorLimits, String> {
+        match self.allowlist.get(investor) {
+            Some(limits) => Ok(limits.or(&self.default_limits)),
+            None if self.allowlist_enabled => Err("Investor is not on the allowlist.".to_string()),
+            None => Ok(self.default_limits),
+        }
+    }
+
+    /// Checks a booking of `quantity` on top of the `booked` tokens the investor already holds in the sale.
+    pub fn check_booking(&self, investor: &Principal, booked: u128, quantity: u128) -> Result<(), String> {
+        let limits = self.limits_of(investor)?;
+        let total = booked + quantity;
+        if let Some(min) = limits.min_tokens.filter(|min| total < *min) {
+            return Err(format!("Bookings must add up to at least {min} tokens per investor."));
+        }
+        if let Some(max) = limits.max_tokens.filter(|max| total > *max) {
+            return Err(format!(
+                "Bookings are limited to {max} tokens per investor; {booked} are booked already."
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist_and_limits() {
+        let listed = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let mut store = ComplianceStore {
+            default_limits: InvestorLimits { min_tokens: Some(2), max_tokens: Some(10) },
+            ..Default::default()
+        };
+        store
+            .update_allowlist(UpdateAllowlistArgs {
+                add: vec![AllowlistEntry { investor: listed, limits: InvestorLimits { min_tokens: None, max_tokens: Some(20) } }],
+                remove: vec![],
+            })
+            .unwrap();
+
+        assert!(store.check_booking(&other, 0, 1).is_err());
+        assert!(store.check_booking(&other, 1, 1).is_ok());
+        assert!(store.check_booking(&other, 5, 6).is_err());
+        // A listed investor keeps the default minimum but has their own maximum.
+        assert!(store.check_booking(&listed, 0, 1).is_err());
+        assert!(store.check_booking(&listed, 5, 15).is_ok());
+
+        store.allowlist_enabled = true;
+        assert!(store.check_booking(&other, 0, 2).is_err());
+        assert!(store.check_booking(&listed, 0, 2).is_ok());
+
+        let invalid = InvestorLimits { min_tokens: Some(3), max_tokens: Some(1) };
+        assert!(store
+            .update_policy(UpdateInvestorPolicyArgs { allowlist_enabled: None, default_limits: Some(invalid) })
+            .is_err());
+    }
+}
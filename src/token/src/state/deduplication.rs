@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use sha2::{Digest, Sha256};
+
+use super::approvals::{
+    ApproveCollectionError, ApproveTokenError, RevokeCollectionApprovalError, RevokeTokenApprovalError, TransferFromError,
+};
+use super::models::{Icrc7BurnRetItemInnerErr, Icrc7TransferRetItemInnerErr};
+use super::settings::CollectionSettings;
+
+/// Why a transaction carrying `created_at_time` was not accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeduplicationError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+}
+
+impl From<DeduplicationError> for Icrc7TransferRetItemInnerErr {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::Duplicate { duplicate_of },
+        }
+    }
+}
+
+impl From<DeduplicationError> for Icrc7BurnRetItemInnerErr {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::Duplicate { duplicate_of },
+        }
+    }
+}
+
+impl From<DeduplicationError> for TransferFromError {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::Duplicate { duplicate_of },
+        }
+    }
+}
+
+/// Approvals and revocations are not deduplicated, so they only ever fail the window.
+impl From<DeduplicationError> for ApproveTokenError {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::GenericError {
+                error_code: Nat::from(1u8),
+                message: format!("Duplicate of block {duplicate_of}."),
+            },
+        }
+    }
+}
+
+impl From<DeduplicationError> for ApproveCollectionError {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::GenericError {
+                error_code: Nat::from(1u8),
+                message: format!("Duplicate of block {duplicate_of}."),
+            },
+        }
+    }
+}
+
+impl From<DeduplicationError> for RevokeTokenApprovalError {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::GenericError {
+                error_code: Nat::from(1u8),
+                message: format!("Duplicate of block {duplicate_of}."),
+            },
+        }
+    }
+}
+
+impl From<DeduplicationError> for RevokeCollectionApprovalError {
+    fn from(err: DeduplicationError) -> Self {
+        match err {
+            DeduplicationError::TooOld => Self::TooOld,
+            DeduplicationError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            DeduplicationError::Duplicate { duplicate_of } => Self::GenericError {
+                error_code: Nat::from(1u8),
+                message: format!("Duplicate of block {duplicate_of}."),
+            },
+        }
+    }
+}
+
+/// Transactions with a `created_at_time` seen within the transaction window.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct RecentTransactions {
+    /// (created_at_time, transaction hash) -> block index
+    entries: BTreeMap<(u64, Vec<u8>), Nat>,
+}
+
+/// Hashes a transaction so that identical requests from the same caller collide.
+/// `method` keeps different endpoints from sharing an entry.
+pub fn transaction_hash<T: CandidType>(method: &str, caller: Principal, arg: &T) -> Vec<u8> {
+    let encoded = candid::encode_args((method, caller, arg)).unwrap_or_default();
+    Sha256::digest(encoded).to_vec()
+}
+
+/// Checks `created_at_time` against the transaction window, allowing for the permitted drift.
+pub fn check_window(created_at_time: u64, now: u64, settings: &CollectionSettings) -> Result<(), DeduplicationError> {
+    if created_at_time.saturating_add(settings.tx_window).saturating_add(settings.permitted_drift) < now {
+        return Err(DeduplicationError::TooOld);
+    }
+    if created_at_time > now.saturating_add(settings.permitted_drift) {
+        return Err(DeduplicationError::CreatedInFuture { ledger_time: now });
+    }
+    Ok(())
+}
+
+impl RecentTransactions {
+    /// Checks `created_at_time` against the window and looks for a duplicate.
+    /// Transactions without `created_at_time` are never deduplicated.
+    pub fn check(
+        &self,
+        created_at_time: Option<u64>,
+        hash: &[u8],
+        now: u64,
+        settings: &CollectionSettings,
+    ) -> Result<(), DeduplicationError> {
+        let Some(created_at_time) = created_at_time else {
+            return Ok(());
+        };
+
+        check_window(created_at_time, now, settings)?;
+        if let Some(duplicate_of) = self.entries.get(&(created_at_time, hash.to_vec())) {
+            return Err(DeduplicationError::Duplicate {
+                duplicate_of: duplicate_of.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, created_at_time: Option<u64>, hash: Vec<u8>, block_index: Nat) {
+        if let Some(created_at_time) = created_at_time {
+            self.entries.insert((created_at_time, hash), block_index);
+        }
+    }
+
+    /// Drops entries that are too old to be matched again.
+    pub fn prune(&mut self, now: u64, settings: &CollectionSettings) {
+        let oldest = now
+            .saturating_sub(settings.tx_window)
+            .saturating_sub(settings.permitted_drift);
+        self.entries = self.entries.split_off(&(oldest, vec![]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    #[test]
+    fn test_window_and_duplicates() {
+        let settings = CollectionSettings::default();
+        let mut recent = RecentTransactions::default();
+        let hash = transaction_hash("icrc7_transfer", Principal::anonymous(), &1u32);
+
+        let too_old = NOW - settings.tx_window - settings.permitted_drift - 1;
+        assert_eq!(recent.check(Some(too_old), &hash, NOW, &settings), Err(DeduplicationError::TooOld));
+
+        let in_future = NOW + settings.permitted_drift + 1;
+        assert_eq!(
+            recent.check(Some(in_future), &hash, NOW, &settings),
+            Err(DeduplicationError::CreatedInFuture { ledger_time: NOW })
+        );
+
+        assert_eq!(recent.check(Some(NOW), &hash, NOW, &settings), Ok(()));
+        recent.insert(Some(NOW), hash.clone(), Nat::from(4u8));
+        assert_eq!(
+            recent.check(Some(NOW), &hash, NOW, &settings),
+            Err(DeduplicationError::Duplicate { duplicate_of: Nat::from(4u8) })
+        );
+        assert_eq!(recent.check(None, &hash, NOW, &settings), Ok(()));
+
+        recent.prune(NOW + settings.tx_window + settings.permitted_drift + 1, &settings);
+        assert_eq!(recent.check(Some(NOW), &hash, NOW, &settings), Ok(()));
+    }
+}
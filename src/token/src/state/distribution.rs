@@ -0,0 +1,348 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::storable::Bound as StorableBound;
+use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg};
+
+use super::account::Account;
+use super::ledger::{self, TransferOutcome};
+use super::memory::DISTRIBUTION_ROUNDS;
+use super::models::Icrc1Account;
+
+/// The subaccount of this canister that revenue is deposited into before it is distributed.
+/// Its first byte is above the longest principal, so it never matches an investor's escrow
+/// subaccount, which starts with the length of the investor's principal.
+pub const DISTRIBUTION_SUBACCOUNT: [u8; 32] = *b"revenue-distribution\0\0\0\0\0\0\0\0\0\0\0\0";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PayoutStatus {
+    /// Not attempted yet.
+    Pending,
+    Paid { block_index: Nat },
+    /// Waiting in the retry queue.
+    Failed { error: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Payout {
+    pub to: Icrc1Account,
+    /// Tokens held by `to` when the round was created.
+    pub tokens: u64,
+    /// What `to` receives; the ledger fee comes on top of it.
+    pub amount: u128,
+    pub status: PayoutStatus,
+    pub attempts: u32,
+    /// Sent with every attempt so that the ledger rejects a second payment as a duplicate.
+    pub created_at_time: u64,
+    /// An attempt did not return, so the payout may have been made. Only a paid attempt or
+    /// `resolve_payout` clears it.
+    pub outcome_unknown: bool,
+}
+
+/// One call to `distribute_revenue`: the funds split over the holders at that moment.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DistributionRound {
+    pub id: u64,
+    pub ledger: Principal,
+    pub created_at: u64,
+    /// Balance of the distribution account that was not owed to earlier rounds.
+    pub available: u128,
+    /// Sum of all payouts, excluding fees.
+    pub distributed: u128,
+    pub fee: u128,
+    pub total_tokens: u64,
+    pub payouts: Vec<Payout>,
+}
+
+/// A payout waiting to be retried.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueuedPayout {
+    pub round_id: u64,
+    pub index: u32,
+    pub payout: Payout,
+}
+
+impl Storable for DistributionRound {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode distribution round"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode distribution round")
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+/// Rounds live in stable memory (`memory::DISTRIBUTION_ROUNDS`); the heap keeps the
+/// round counter, the retry queue and whether payouts are being sent.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct DistributionStore {
+    pub next_round_id: u64,
+    /// (round id, payout index) of every failed payout.
+    pub retry_queue: BTreeSet<(u64, u32)>,
+    pub in_progress: bool,
+}
+
+pub fn distribution_account() -> Icrc1Account {
+    Icrc1Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(DISTRIBUTION_SUBACCOUNT.to_vec()),
+    }
+}
+
+/// Splits `distributable` over `holders` in proportion to their tokens, rounding down.
+/// Holders whose share rounds to zero are left out; the remainder stays for the next round.
+pub fn split(distributable: u128, holders: &BTreeMap<Account, u64>, created_at_time: u64) -> Vec<Payout> {
+    let total_tokens: u128 = holders.values().map(|tokens| *tokens as u128).sum();
+    if total_tokens == 0 {
+        return vec![];
+    }
+    holders
+        .iter()
+        .map(|(account, tokens)| Payout {
+            to: account.into(),
+            tokens: *tokens,
+            amount: distributable * *tokens as u128 / total_tokens,
+            status: PayoutStatus::Pending,
+            attempts: 0,
+            created_at_time,
+            outcome_unknown: false,
+        })
+        .filter(|payout| payout.amount > 0)
+        .collect()
+}
+
+impl DistributionStore {
+    /// Marks payouts as being sent, so that rounds and retries do not overlap.
+    pub fn lock(&mut self) -> Result<(), String> {
+        if self.in_progress {
+            return Err("A distribution is already in progress.".to_string());
+        }
+        self.in_progress = true;
+        Ok(())
+    }
+
+    pub fn unlock(&mut self) {
+        self.in_progress = false;
+    }
+
+    pub fn get_round(&self, round_id: u64) -> Option<DistributionRound> {
+        DISTRIBUTION_ROUNDS.with_borrow(|rounds| rounds.get(&round_id))
+    }
+
+    /// Up to `take` rounds in ascending order, starting after `prev`.
+    pub fn rounds(&self, prev: Option<u64>, take: usize) -> Vec<DistributionRound> {
+        let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+        DISTRIBUTION_ROUNDS.with_borrow(|rounds| {
+            rounds
+                .range((start, Bound::Unbounded))
+                .take(take)
+                .map(|(_, round)| round)
+                .collect()
+        })
+    }
+
+    /// Funds in the distribution account that still belong to queued payouts, fees included.
+    pub fn reserved(&self) -> u128 {
+        self.retry_queue
+            .iter()
+            .filter_map(|(round_id, index)| {
+                let round = self.get_round(*round_id)?;
+                Some(round.payouts.get(*index as usize)?.amount + round.fee)
+            })
+            .sum()
+    }
+
+    pub fn queued(&self) -> Vec<QueuedPayout> {
+        self.retry_queue
+            .iter()
+            .filter_map(|(round_id, index)| {
+                let payout = self.get_round(*round_id)?.payouts.get(*index as usize)?.clone();
+                Some(QueuedPayout {
+                    round_id: *round_id,
+                    index: *index,
+                    payout,
+                })
+            })
+            .collect()
+    }
+
+    /// Records a new round splitting `balance`, less what queued payouts still need,
+    /// over `holders`. Every payout's `fee` is taken from the balance first.
+    pub fn create_round(
+        &mut self,
+        ledger: Principal,
+        fee: u128,
+        balance: u128,
+        holders: &BTreeMap<Account, u64>,
+        now: u64,
+    ) -> Result<DistributionRound, String> {
+        let available = balance.saturating_sub(self.reserved());
+        let fees = fee * holders.len() as u128;
+        if available <= fees {
+            return Err(format!(
+                "Nothing to distribute: {available} available, {fees} needed for fees."
+            ));
+        }
+
+        let payouts = split(available - fees, holders, now);
+        let round = DistributionRound {
+            id: self.next_round_id,
+            ledger,
+            created_at: now,
+            available,
+            distributed: payouts.iter().map(|payout| payout.amount).sum(),
+            fee,
+            total_tokens: holders.values().sum(),
+            payouts,
+        };
+        self.next_round_id += 1;
+        DISTRIBUTION_ROUNDS.with_borrow_mut(|rounds| rounds.insert(round.id, round.clone()));
+        Ok(round)
+    }
+
+    /// Stores the outcome of an attempt and keeps the retry queue in step with it.
+    pub fn record_attempt(&mut self, round_id: u64, index: u32, outcome: TransferOutcome) {
+        let Some(mut round) = self.get_round(round_id) else {
+            return;
+        };
+        let Some(payout) = round.payouts.get_mut(index as usize) else {
+            return;
+        };
+        if matches!(payout.status, PayoutStatus::Paid { .. }) {
+            return;
+        }
+
+        payout.attempts += 1;
+        match outcome {
+            TransferOutcome::Paid(block_index) => {
+                payout.status = PayoutStatus::Paid { block_index };
+                payout.outcome_unknown = false;
+                self.retry_queue.remove(&(round_id, index));
+            }
+            TransferOutcome::Rejected(error) => {
+                payout.status = PayoutStatus::Failed { error };
+                self.retry_queue.insert((round_id, index));
+            }
+            TransferOutcome::Unknown(error) => {
+                payout.status = PayoutStatus::Failed { error };
+                payout.outcome_unknown = true;
+                self.retry_queue.insert((round_id, index));
+            }
+        }
+        DISTRIBUTION_ROUNDS.with_borrow_mut(|rounds| rounds.insert(round_id, round));
+    }
+
+    /// Takes up a queued payout again. Once the ledger no longer deduplicates its `created_at_time`,
+    /// a refused payout gets a new one, and one that may have been made waits for `resolve_payout`.
+    pub fn prepare_retry(&mut self, round_id: u64, index: u32, now: u64) -> Option<(Principal, u128, Payout)> {
+        let mut round = self.get_round(round_id)?;
+        let payout = round.payouts.get_mut(index as usize)?;
+        if !ledger::is_deduplicated(payout.created_at_time, now) {
+            if payout.outcome_unknown {
+                return None;
+            }
+            payout.created_at_time = now;
+        }
+        let payout = payout.clone();
+        let (ledger, fee) = (round.ledger, round.fee);
+        DISTRIBUTION_ROUNDS.with_borrow_mut(|rounds| rounds.insert(round_id, round));
+        Some((ledger, fee, payout))
+    }
+
+    /// Settles a payout that may have been made, as found on the ledger: paid in `block_index`,
+    /// or not made, in which case it is retried once the ledger can no longer accept the lost attempt.
+    pub fn resolve_payout(&mut self, round_id: u64, index: u32, block_index: Option<Nat>, now: u64) -> Result<Payout, String> {
+        if self.in_progress {
+            return Err("A distribution is in progress, try again.".to_string());
+        }
+        let mut round = self.get_round(round_id).ok_or("Distribution round not found".to_string())?;
+        let payout = round
+            .payouts
+            .get_mut(index as usize)
+            .filter(|payout| payout.outcome_unknown && !matches!(payout.status, PayoutStatus::Paid { .. }))
+            .ok_or(format!("Payout {index} of round {round_id} is not waiting to be resolved."))?;
+
+        match block_index {
+            Some(block_index) => {
+                payout.status = PayoutStatus::Paid { block_index };
+                self.retry_queue.remove(&(round_id, index));
+            }
+            None => ledger::check_expired(payout.created_at_time, now)?,
+        }
+        payout.outcome_unknown = false;
+        let payout = payout.clone();
+        DISTRIBUTION_ROUNDS.with_borrow_mut(|rounds| rounds.insert(round_id, round));
+        Ok(payout)
+    }
+}
+
+/// Sends one payout from the distribution account. The memo identifies the round and
+/// payout, and a duplicate reported by the ledger counts as paid.
+pub async fn send_payout(ledger: Principal, fee: u128, round_id: u64, index: u32, payout: &Payout) -> TransferOutcome {
+    let mut memo = round_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(&index.to_be_bytes());
+
+    let args = TransferArg {
+        from_subaccount: Some(DISTRIBUTION_SUBACCOUNT),
+        to: LedgerAccount {
+            owner: payout.to.owner,
+            subaccount: Some(Account::from(&payout.to).subaccount),
+        },
+        fee: Some(fee.into()),
+        created_at_time: Some(payout.created_at_time),
+        memo: Some(Memo::from(memo)),
+        amount: payout.amount.into(),
+    };
+
+    ledger::transfer(ledger, args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_is_pro_rata() {
+        let mut holders = BTreeMap::new();
+        holders.insert(Account::new(Principal::from_slice(&[1]), &None), 3);
+        holders.insert(Account::new(Principal::from_slice(&[2]), &None), 1);
+        holders.insert(Account::new(Principal::from_slice(&[3]), &None), 0);
+
+        let payouts = split(1_001, &holders, 7);
+        let amounts: Vec<u128> = payouts.iter().map(|payout| payout.amount).collect();
+        assert_eq!(amounts, vec![750, 250]);
+        assert!(payouts.iter().all(|payout| payout.status == PayoutStatus::Pending && payout.created_at_time == 7));
+
+        assert!(split(1_000, &BTreeMap::new(), 7).is_empty());
+    }
+
+    #[test]
+    fn test_payouts_that_may_have_been_made_wait_to_be_resolved() {
+        let mut holders = BTreeMap::new();
+        holders.insert(Account::new(Principal::from_slice(&[1]), &None), 1);
+        let mut store = DistributionStore::default();
+        let round = store.create_round(Principal::from_slice(&[10]), 10, 1_010, &holders, 100).unwrap();
+
+        store.record_attempt(round.id, 0, TransferOutcome::Unknown("call failed".to_string()));
+        // A refusal after a lost attempt does not show that the lost one failed too.
+        store.record_attempt(round.id, 0, TransferOutcome::Rejected("bad fee".to_string()));
+        let (_, _, payout) = store.prepare_retry(round.id, 0, 200).unwrap();
+        assert_eq!((payout.created_at_time, payout.outcome_unknown), (100, true));
+
+        // Past the ledger's window it is neither sent again nor taken as failed too early.
+        let later = 100 + ledger::LEDGER_TX_WINDOW + 1;
+        assert!(store.prepare_retry(round.id, 0, later).is_none());
+        assert!(store.resolve_payout(round.id, 0, None, later).is_err());
+
+        let payout = store.resolve_payout(round.id, 0, Some(Nat::from(9u8)), later).unwrap();
+        assert_eq!(payout.status, PayoutStatus::Paid { block_index: Nat::from(9u8) });
+        assert!(store.queued().is_empty());
+        assert!(store.resolve_payout(round.id, 0, None, later).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg};
+
+use super::account::Account;
+use super::ledger::{self, TransferOutcome};
+use super::memory::HOLDER_DIVIDENDS;
+use super::models::Icrc1Account;
+
+/// The subaccount that claimable revenue is deposited into. Kept apart from
+/// `DISTRIBUTION_SUBACCOUNT` so that pushed and claimable revenue never mix.
+pub const DIVIDEND_SUBACCOUNT: [u8; 32] = *b"revenue-dividends\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// `revenue_per_token` is scaled by this so that small deposits over many tokens still count.
+const MAGNITUDE: u128 = 1_000_000_000_000_000_000;
+
+/// What one holder has earned so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HolderDividend {
+    /// Earned up to the last settlement and not claimed yet.
+    pub settled: u128,
+    /// `revenue_per_token` at the last settlement.
+    pub checkpoint: u128,
+}
+
+impl Storable for HolderDividend {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.settled.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.checkpoint.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (settled, checkpoint) = bytes.split_at(16);
+        Self {
+            settled: u128::from_le_bytes(settled.try_into().expect("Invalid settled amount")),
+            checkpoint: u128::from_le_bytes(checkpoint.try_into().expect("Invalid checkpoint")),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// A claim taken out of the books and not confirmed by the ledger yet. It is sent again
+/// with the same `created_at_time` and memo while the ledger deduplicates it, and after that
+/// waits for `resolve_claim`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingClaim {
+    pub id: u64,
+    pub amount: u128,
+    pub fee: u128,
+    pub created_at_time: u64,
+    /// Set while a `claim` call waits for the ledger.
+    pub in_flight: bool,
+    /// An attempt did not return, so the claim may have been paid and is never credited back
+    /// unless `resolve_claim` finds it was not.
+    pub outcome_unknown: bool,
+}
+
+/// Revenue that holders claim themselves. Every deposit raises `revenue_per_token`;
+/// a holder's share is settled into `memory::HOLDER_DIVIDENDS` whenever their balance
+/// is about to change, so tokens only earn while they are held.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct Dividends {
+    /// Cumulative revenue per token, scaled by `MAGNITUDE`.
+    pub revenue_per_token: u128,
+    /// Funds in the dividend account that have been accrued and not claimed yet.
+    pub accounted: u128,
+    /// Claims that are not paid yet, by holder.
+    pub pending_claims: BTreeMap<Account, PendingClaim>,
+    pub next_claim_id: u64,
+    pub accruing: bool,
+}
+
+pub fn dividend_account() -> Icrc1Account {
+    Icrc1Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(DIVIDEND_SUBACCOUNT.to_vec()),
+    }
+}
+
+impl Dividends {
+    /// What `account` can claim given the `balance` it holds now.
+    pub fn claimable(&self, account: &Account, balance: u64) -> u128 {
+        let holder = HOLDER_DIVIDENDS.with_borrow(|holders| holders.get(account)).unwrap_or_default();
+        holder.settled + self.earned_since(&holder, balance)
+    }
+
+    /// Claims not confirmed by the ledger yet, with the account they pay.
+    pub fn pending_claims(&self) -> Vec<(Icrc1Account, PendingClaim)> {
+        self.pending_claims
+            .iter()
+            .map(|(account, claim)| (Icrc1Account::from(account), claim.clone()))
+            .collect()
+    }
+
+    fn earned_since(&self, holder: &HolderDividend, balance: u64) -> u128 {
+        (self.revenue_per_token - holder.checkpoint) * balance as u128 / MAGNITUDE
+    }
+
+    /// Moves what `account` earned with its current `balance` into its settled amount.
+    /// Must be called before the balance changes.
+    pub fn settle(&self, account: &Account, balance: u64) {
+        HOLDER_DIVIDENDS.with_borrow_mut(|holders| {
+            let mut holder = holders.get(account).unwrap_or_default();
+            holder.settled += self.earned_since(&holder, balance);
+            holder.checkpoint = self.revenue_per_token;
+            holders.insert(*account, holder);
+        });
+    }
+
+    /// Balance reads and claims would miscount each other, so they never overlap.
+    pub fn begin_accrual(&mut self) -> Result<(), String> {
+        if self.accruing {
+            return Err("Revenue is already being accrued.".to_string());
+        }
+        if self.pending_claims.values().any(|claim| claim.in_flight) {
+            return Err("Claims are in progress, try again.".to_string());
+        }
+        self.accruing = true;
+        Ok(())
+    }
+
+    /// Spreads whatever arrived in the dividend account since the last accrual over
+    /// `total_supply` tokens. Returns the amount accrued.
+    pub fn accrue(&mut self, balance: u128, total_supply: u64) -> u128 {
+        self.accruing = false;
+        // Pending claims may still be in the account, or may have left it already.
+        let pending: u128 = self.pending_claims.values().map(|claim| claim.amount).sum();
+        let deposited = balance.saturating_sub(self.accounted + pending);
+        if deposited == 0 || total_supply == 0 {
+            return 0;
+        }
+        self.revenue_per_token += deposited * MAGNITUDE / total_supply as u128;
+        self.accounted += deposited;
+        deposited
+    }
+
+    pub fn cancel_accrual(&mut self) {
+        self.accruing = false;
+    }
+
+    /// Takes everything `account` can claim out of the books as a pending claim, or returns
+    /// its earlier pending claim so that the same transfer is sent again.
+    pub fn begin_claim(&mut self, account: &Account, balance: u64, fee: u128, now: u64) -> Result<PendingClaim, String> {
+        if self.accruing {
+            return Err("Revenue is being accrued, try again.".to_string());
+        }
+        if let Some(claim) = self.pending_claims.get_mut(account) {
+            if claim.in_flight {
+                return Err("A claim for this account is already in progress.".to_string());
+            }
+            // Past the ledger's deduplication window a retry could pay the claim twice.
+            if !ledger::is_deduplicated(claim.created_at_time, now) {
+                return Err(format!(
+                    "Claim {} may have been paid; it waits for the collection owner to resolve it.",
+                    claim.id
+                ));
+            }
+            claim.in_flight = true;
+            return Ok(claim.clone());
+        }
+
+        self.settle(account, balance);
+        let amount = HOLDER_DIVIDENDS.with_borrow(|holders| holders.get(account)).unwrap_or_default().settled;
+        if amount <= fee {
+            return Err(format!("Nothing to claim: {amount} is not more than the fee of {fee}."));
+        }
+
+        HOLDER_DIVIDENDS.with_borrow_mut(|holders| {
+            if let Some(mut holder) = holders.get(account) {
+                holder.settled = 0;
+                holders.insert(*account, holder);
+            }
+        });
+        self.accounted -= amount;
+
+        let claim = PendingClaim {
+            id: self.next_claim_id,
+            amount,
+            fee,
+            created_at_time: now,
+            in_flight: true,
+            outcome_unknown: false,
+        };
+        self.next_claim_id += 1;
+        self.pending_claims.insert(*account, claim.clone());
+        Ok(claim)
+    }
+
+    /// Drops a paid claim and puts a rejected one back. A claim that may have been paid by an
+    /// earlier attempt stays pending for the next `claim` however this one ended.
+    pub fn end_claim(&mut self, account: &Account, outcome: &TransferOutcome) {
+        let Some(claim) = self.pending_claims.get_mut(account) else {
+            return;
+        };
+        claim.in_flight = false;
+        match outcome {
+            TransferOutcome::Paid(_) => {
+                self.pending_claims.remove(account);
+            }
+            TransferOutcome::Unknown(_) => claim.outcome_unknown = true,
+            TransferOutcome::Rejected(_) if claim.outcome_unknown => {}
+            TransferOutcome::Rejected(_) => self.credit_back(account),
+        }
+    }
+
+    /// Settles a claim that may have been paid, as found on the ledger: paid in `block_index`,
+    /// or not paid, in which case it is credited back once the ledger can no longer accept it.
+    pub fn resolve_claim(&mut self, account: &Account, block_index: Option<Nat>, now: u64) -> Result<PendingClaim, String> {
+        if self.accruing {
+            return Err("Revenue is being accrued, try again.".to_string());
+        }
+        let claim = self
+            .pending_claims
+            .get(account)
+            .filter(|claim| claim.outcome_unknown && !claim.in_flight)
+            .cloned()
+            .ok_or("No claim of this account is waiting to be resolved.".to_string())?;
+
+        match block_index {
+            Some(_) => {
+                self.pending_claims.remove(account);
+            }
+            None => {
+                ledger::check_expired(claim.created_at_time, now)?;
+                self.credit_back(account);
+            }
+        }
+        Ok(claim)
+    }
+
+    fn credit_back(&mut self, account: &Account) {
+        let Some(claim) = self.pending_claims.remove(account) else {
+            return;
+        };
+        HOLDER_DIVIDENDS.with_borrow_mut(|holders| {
+            let mut holder = holders.get(account).unwrap_or_default();
+            holder.settled += claim.amount;
+            holders.insert(*account, holder);
+        });
+        self.accounted += claim.amount;
+    }
+}
+
+/// Sends a pending claim to `account`; the ledger fee is taken out of it. The memo is the
+/// claim id, and a duplicate reported by the ledger counts as paid.
+pub async fn send_claim(ledger: Principal, account: &Account, claim: &PendingClaim) -> TransferOutcome {
+    let args = TransferArg {
+        from_subaccount: Some(DIVIDEND_SUBACCOUNT),
+        to: LedgerAccount {
+            owner: account.owner,
+            subaccount: Some(account.subaccount),
+        },
+        fee: Some(claim.fee.into()),
+        created_at_time: Some(claim.created_at_time),
+        memo: Some(Memo::from(claim.id.to_be_bytes().to_vec())),
+        amount: (claim.amount - claim.fee).into(),
+    };
+
+    ledger::transfer(ledger, args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{TokenMetadata, TokenState};
+    use super::*;
+
+    #[test]
+    fn test_seller_keeps_what_accrued_before_a_transfer() {
+        let mut tokens = TokenState::new();
+        let seller = Principal::from_slice(&[1]);
+        let buyer = Principal::from_slice(&[2]);
+        let (seller_account, buyer_account) = (Account::new(seller, &None), Account::new(buyer, &None));
+        tokens.mint(seller, None, TokenMetadata::new());
+        tokens.mint(seller, None, TokenMetadata::new());
+
+        assert_eq!(tokens.dividends.accrue(1_000_000, tokens.count()), 1_000_000);
+        tokens.transfer(1, buyer, None);
+        // Nothing new was deposited, so the balance is accrued already.
+        assert_eq!(tokens.dividends.accrue(1_000_000, tokens.count()), 0);
+        assert_eq!(tokens.dividends.accrue(1_400_000, tokens.count()), 400_000);
+
+        let claimable = |tokens: &TokenState, account: &Account| {
+            tokens.dividends.claimable(account, tokens.balance_of(account))
+        };
+        assert_eq!(claimable(&tokens, &seller_account), 1_200_000);
+        assert_eq!(claimable(&tokens, &buyer_account), 200_000);
+
+        let claim = tokens.dividends.begin_claim(&seller_account, 1, 10_000, 0).unwrap();
+        assert_eq!(claim.amount, 1_200_000);
+        assert_eq!(claimable(&tokens, &seller_account), 0);
+        assert!(tokens.dividends.begin_accrual().is_err());
+
+        tokens.dividends.end_claim(&seller_account, &TransferOutcome::Rejected("insufficient funds".to_string()));
+        assert_eq!(claimable(&tokens, &seller_account), 1_200_000);
+        assert_eq!(tokens.dividends.accounted, 1_400_000);
+    }
+
+    #[test]
+    fn test_unknown_claims_are_retried_not_credited_back() {
+        let mut dividends = Dividends::default();
+        let holder = Account::new(Principal::from_slice(&[1]), &None);
+        dividends.accrue(1_000_000, 1);
+
+        let claim = dividends.begin_claim(&holder, 1, 10_000, 100).unwrap();
+        assert!(dividends.begin_claim(&holder, 1, 10_000, 200).is_err());
+        dividends.end_claim(&holder, &TransferOutcome::Unknown("call failed".to_string()));
+        assert_eq!(dividends.claimable(&holder, 1), 0);
+        // The claim may have left the account, so it is not accrued again.
+        assert_eq!(dividends.accrue(1_000_000, 1), 0);
+
+        let retry = dividends.begin_claim(&holder, 1, 20_000, 200).unwrap();
+        assert_eq!((retry.id, retry.fee, retry.created_at_time), (claim.id, 10_000, 100));
+        // A refusal after a lost attempt does not show that the lost one failed too.
+        dividends.end_claim(&holder, &TransferOutcome::Rejected("bad fee".to_string()));
+        assert_eq!(dividends.claimable(&holder, 1), 0);
+
+        // Past the ledger's window it is neither sent again nor credited back too early.
+        let later = 100 + ledger::LEDGER_TX_WINDOW + 1;
+        assert!(dividends.begin_claim(&holder, 1, 10_000, later).is_err());
+        assert!(dividends.resolve_claim(&holder, None, later).is_err());
+        let expired = later + 2 * ledger::LEDGER_PERMITTED_DRIFT;
+        assert_eq!(dividends.resolve_claim(&holder, None, expired).unwrap().id, claim.id);
+        assert!(dividends.pending_claims.is_empty());
+        assert_eq!(dividends.claimable(&holder, 1), 1_000_000);
+    }
+}
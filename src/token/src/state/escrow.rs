@@ -1,18 +1,25 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::call::call;
-use ic_ledger_types::{Memo, Tokens, DEFAULT_FEE};
+use ic_ledger_types::{Tokens, MAINNET_LEDGER_CANISTER_ID};
 use serde::Serialize;
-use std::collections::HashMap;
-use ic_ledger_types::{transfer, TransferArgs};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use icrc_ledger_types::icrc1::{account::Account as LedgerAccount, transfer::{Memo, TransferArg}};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
 
-use crate::{state::{index_canister::{self, Account}, subaccount::{AccountIdentifier, Subaccount}}, Icrc1Account};
+use crate::{state::{ledger::{self, TransferOutcome}, subaccount::Subaccount}, Icrc1Account};
 
-use super::metadata::Metadata;
+use super::memory::{BOOKED_TOKENS, BOOKINGS};
+use super::metadata::{AcceptedLedger, Metadata, MinRaise};
+use super::settlement::{self, Settlement};
 
 /// Sale Status Enum
 #[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum SaleStatus {
     Live,
+    /// `sale_end` has passed; bookings are closed until the sale is accepted or rejected.
+    Closed,
+    /// `accept_sale` has started charging investors and must be called until all of them are settled.
+    Settling,
     Accepted,
     Rejected,
 }
@@ -23,12 +30,168 @@ impl Default for SaleStatus {
     }
 }
 
+/// How far the sale has come, for `get_sale_summary`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SaleSummary {
+    pub sale_status: SaleStatus,
+    pub total_booked_tokens: u128,
+    pub supply_cap: u128,
+    /// What the booked tokens cost at the prices they were booked for, in ledger units.
+    pub raised: u128,
+    pub min_raise: Option<MinRaise>,
+    pub min_raise_reached: bool,
+    pub sale_start: Option<u64>,
+    pub sale_end: Option<u64>,
+}
+
+/// One call to `book_tokens` or `cancel_booking`, with the amount it locked in or freed.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct Booking {
+    pub id: u64,
+    pub kind: BookingKind,
+    pub quantity: u128,
+    /// What the tokens cost when they were booked, or what cancelling them freed, in units of `ledger`.
+    pub amount: u128,
+    pub booked_at: u64,
+    /// The ledger block that paid for a booking made with `BookingPayment::TransferFrom`.
+    pub block_index: Option<Nat>,
+    /// The ledger the booking was paid in; `Metadata.token` if not set.
+    pub ledger: Option<Principal>,
+}
+
+/// What an investor has booked in one ledger and owes for it there.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Position {
+    pub quantity: u128,
+    pub amount: u128,
+}
+
+/// One investor's escrow in one ledger set against what they owe there, in ledger units.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReconciliationRow {
+    pub investor: Principal,
+    pub ledger: Principal,
+    pub escrow_balance: u128,
+    pub booked_quantity: u128,
+    pub amount_owed: u128,
+    /// One ledger fee per booked token, kept back for settling or refunding them.
+    pub fee_reserve: u128,
+    pub excess: u128,
+    pub shortfall: u128,
+}
+
+impl ReconciliationRow {
+    pub fn new(investor: Principal, ledger: Principal, position: Position, escrow_balance: u128, fee: u128) -> Self {
+        let fee_reserve = position.quantity * fee;
+        let required = position.amount + fee_reserve;
+        Self {
+            investor,
+            ledger,
+            escrow_balance,
+            booked_quantity: position.quantity,
+            amount_owed: position.amount,
+            fee_reserve,
+            excess: escrow_balance.saturating_sub(required),
+            shortfall: required.saturating_sub(escrow_balance),
+        }
+    }
+}
+
+/// The rows of one ledger added up.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReconciliationTotal {
+    pub ledger: Principal,
+    pub escrow_balance: u128,
+    pub booked_quantity: u128,
+    pub amount_owed: u128,
+    pub fee_reserve: u128,
+    pub excess: u128,
+    pub shortfall: u128,
+}
+
+impl ReconciliationTotal {
+    pub fn new(ledger: Principal) -> Self {
+        Self {
+            ledger,
+            escrow_balance: 0,
+            booked_quantity: 0,
+            amount_owed: 0,
+            fee_reserve: 0,
+            excess: 0,
+            shortfall: 0,
+        }
+    }
+
+    pub fn add(&mut self, row: &ReconciliationRow) {
+        self.escrow_balance += row.escrow_balance;
+        self.booked_quantity += row.booked_quantity;
+        self.amount_owed += row.amount_owed;
+        self.fee_reserve += row.fee_reserve;
+        self.excess += row.excess;
+        self.shortfall += row.shortfall;
+    }
+}
+
+/// What `reconcile_escrow` found, per investor and ledger and for the whole collection.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EscrowReconciliation {
+    pub sale_status: SaleStatus,
+    pub rows: Vec<ReconciliationRow>,
+    /// One per accepted ledger, in the order of `Metadata::currencies`.
+    pub totals: Vec<ReconciliationTotal>,
+}
+
+/// How `book_tokens` is paid for.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum BookingPayment {
+    /// The investor deposited into their escrow account beforehand.
+    Escrow,
+    /// The token canister pulls the exact cost into escrow with `icrc2_transfer_from`,
+    /// using an allowance the investor approved on the ledger for this account.
+    TransferFrom { from_subaccount: Option<Vec<u8>> },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BookingKind {
+    Booked,
+    /// `refund_block_index` is set once the freed funds have left escrow.
+    Cancelled { refund_block_index: Option<Nat> },
+}
+
+/// The refund of a cancelled booking that the ledger has not confirmed yet. It is sent with the
+/// booking's id as memo and, while the ledger deduplicates it, with the same `created_at_time`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRefund {
+    pub investor: Principal,
+    pub ledger: Principal,
+    pub to: Icrc1Account,
+    /// What reaches `to`; the fee comes on top of it.
+    pub amount: u128,
+    pub fee: u128,
+    pub created_at_time: u64,
+    /// Set while a call waits for the ledger.
+    pub in_flight: bool,
+    /// An attempt did not return, so the refund may have been sent and is never sent again
+    /// past the ledger's deduplication window.
+    pub outcome_unknown: bool,
+    pub last_error: Option<String>,
+}
+
 /// Escrow Store Struct
+/// Booked quantities and the bookings behind them live in stable memory
+/// (`memory::BOOKED_TOKENS` and `memory::BOOKINGS`).
 #[derive(CandidType, Serialize, Deserialize, Debug, Default, Clone)]
 pub struct EscrowStore {
     pub sale_status: SaleStatus,
-    pub booked_tokens: HashMap<Principal, u128>, // Using `String` for Principal text representation
     pub total_booked_tokens: u128,
+    pub next_booking_id: u64,
+    /// Where investors asked for refunds to be sent.
+    pub refund_accounts: BTreeMap<Principal, Icrc1Account>,
+    /// Investors whose `icrc2_transfer_from` payment has not returned yet. Their bookings
+    /// cannot be cancelled or settled until it does.
+    pub pulls_in_flight: BTreeSet<Principal>,
+    /// booking id -> the refund of that cancelled booking, until the ledger pays it
+    pub pending_refunds: BTreeMap<u64, PendingRefund>,
 }
 
 impl EscrowStore {
@@ -36,8 +199,11 @@ impl EscrowStore {
     pub fn default() -> Self {
         Self {
             sale_status: SaleStatus::default(),
-            booked_tokens: HashMap::new(),
             total_booked_tokens: 0,
+            next_booking_id: 0,
+            refund_accounts: BTreeMap::new(),
+            pulls_in_flight: BTreeSet::new(),
+            pending_refunds: BTreeMap::new(),
         }
     }
 
@@ -46,13 +212,17 @@ impl EscrowStore {
         &self.sale_status
     }
 
-    /// Get a read-only view of the booked tokens
-    pub fn get_booked_tokens(&self) -> &HashMap<Principal, u128> {
-        &self.booked_tokens
+    /// Get a snapshot of the booked tokens
+    pub fn get_booked_tokens(&self) -> HashMap<Principal, u128> {
+        BOOKED_TOKENS.with_borrow(|booked| booked.iter().collect())
+    }
+
+    pub fn booked_tokens_of(&self, investor: &Principal) -> u128 {
+        BOOKED_TOKENS.with_borrow(|booked| booked.get(investor).unwrap_or(0))
     }
 
     pub fn get_participating_investors(&self) -> Vec<Principal> {
-        self.booked_tokens.iter().map(|f| f.0.clone()).collect()
+        BOOKED_TOKENS.with_borrow(|booked| booked.iter().map(|(investor, _)| investor).collect())
     }
 
     /// Get the total number of booked tokens
@@ -62,12 +232,286 @@ impl EscrowStore {
 
     /// Book tokens for a specific owner
     pub fn book_tokens(&mut self, owner: Principal, quantity: u128) {
-        let owner_key = owner.clone();
-        let current_amount = self.booked_tokens.get(&owner_key).cloned().unwrap_or(0);
-        self.booked_tokens.insert(owner_key, current_amount + quantity);
+        BOOKED_TOKENS.with_borrow_mut(|booked| {
+            let current_amount = booked.get(&owner).unwrap_or(0);
+            booked.insert(owner, current_amount + quantity);
+        });
         self.total_booked_tokens += quantity;
     }
 
+    fn insert_booking(&mut self, investor: Principal, ledger: Principal, kind: BookingKind, quantity: u128, amount: u128, now: u64) -> Booking {
+        let booking = Booking {
+            id: self.next_booking_id,
+            kind,
+            quantity,
+            amount,
+            booked_at: now,
+            block_index: None,
+            ledger: Some(ledger),
+        };
+        self.next_booking_id += 1;
+        BOOKINGS.with_borrow_mut(|bookings| bookings.insert((investor, booking.id), booking.clone()));
+        booking
+    }
+
+    /// Books `quantity` tokens for `investor` and records the `amount` they cost in `ledger`.
+    pub fn record_booking(&mut self, investor: Principal, ledger: Principal, quantity: u128, amount: u128, now: u64) -> Booking {
+        self.book_tokens(investor, quantity);
+        self.insert_booking(investor, ledger, BookingKind::Booked, quantity, amount, now)
+    }
+
+    /// Takes `quantity` tokens off what the investor booked in `ledger`. The freed amount is their
+    /// average price per token there, so tiers do not make earlier bookings cheaper to cancel.
+    pub fn cancel_booking(&mut self, investor: Principal, ledger: Principal, quantity: u128, metadata: &Metadata, now: u64) -> Result<Booking, String> {
+        self.check_no_pull(&investor)?;
+        let position = self.position(&investor, ledger, metadata);
+        if quantity == 0 || quantity > position.quantity {
+            return Err(format!("Cannot cancel {quantity} tokens; {} are booked in {ledger}.", position.quantity));
+        }
+        let freed = position.amount * quantity / position.quantity;
+
+        let booked = self.booked_tokens_of(&investor);
+        BOOKED_TOKENS.with_borrow_mut(|booked_tokens| booked_tokens.insert(investor, booked - quantity));
+        self.total_booked_tokens -= quantity;
+        let kind = BookingKind::Cancelled { refund_block_index: None };
+        Ok(self.insert_booking(investor, ledger, kind, quantity, freed, now))
+    }
+
+    /// Locks the investor's bookings while a payment is pulled for one of them.
+    pub fn begin_pull(&mut self, investor: Principal) -> Result<(), String> {
+        if !self.pulls_in_flight.insert(investor) {
+            return Err("A payment for an earlier booking is still being pulled, try again.".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn end_pull(&mut self, investor: &Principal) {
+        self.pulls_in_flight.remove(investor);
+    }
+
+    fn check_no_pull(&self, investor: &Principal) -> Result<(), String> {
+        if self.pulls_in_flight.contains(investor) {
+            return Err("A payment for a booking is still being pulled, try again.".to_string());
+        }
+        Ok(())
+    }
+
+    /// Takes back a booking whose payment could not be pulled.
+    pub fn revert_booking(&mut self, investor: Principal, booking: &Booking) {
+        BOOKED_TOKENS.with_borrow_mut(|booked| {
+            let current = booked.get(&investor).unwrap_or(0);
+            booked.insert(investor, current.saturating_sub(booking.quantity));
+        });
+        self.total_booked_tokens = self.total_booked_tokens.saturating_sub(booking.quantity);
+        BOOKINGS.with_borrow_mut(|bookings| bookings.remove(&(investor, booking.id)));
+    }
+
+    pub fn set_payment_block_index(&mut self, investor: Principal, booking_id: u64, block_index: Nat) {
+        BOOKINGS.with_borrow_mut(|bookings| {
+            if let Some(mut booking) = bookings.get(&(investor, booking_id)) {
+                booking.block_index = Some(block_index);
+                bookings.insert((investor, booking_id), booking);
+            }
+        });
+    }
+
+    pub fn set_refund_block_index(&mut self, investor: Principal, booking_id: u64, block_index: Nat) {
+        BOOKINGS.with_borrow_mut(|bookings| {
+            if let Some(mut booking) = bookings.get(&(investor, booking_id)) {
+                booking.kind = BookingKind::Cancelled { refund_block_index: Some(block_index) };
+                bookings.insert((investor, booking_id), booking);
+            }
+        });
+    }
+
+    /// Records the refund of the cancelled `booking`, about to be sent for the first time.
+    pub fn begin_refund(&mut self, investor: Principal, booking: &Booking, amount: u128, fee: u128, ledger: Principal) -> PendingRefund {
+        let refund = PendingRefund {
+            investor,
+            ledger,
+            to: self.refund_account(&investor),
+            amount,
+            fee,
+            created_at_time: booking.booked_at,
+            in_flight: true,
+            outcome_unknown: false,
+            last_error: None,
+        };
+        self.pending_refunds.insert(booking.id, refund.clone());
+        refund
+    }
+
+    /// Takes up the pending refund of booking `booking_id` again. One that may have been sent is
+    /// sent unchanged while the ledger deduplicates it, and dropped after that: whatever is still
+    /// in escrow is then refunded as excess after the sale. Any other one is sent with the current
+    /// `fee` and refund account, and a new `created_at_time` once the old one has expired.
+    pub fn retry_refund(&mut self, investor: Principal, booking_id: u64, fee: u128, now: u64) -> Result<PendingRefund, String> {
+        let to = self.refund_account(&investor);
+        let refund = self
+            .pending_refunds
+            .get_mut(&booking_id)
+            .filter(|refund| refund.investor == investor)
+            .ok_or(format!("Booking {booking_id} has no refund to send."))?;
+        if refund.in_flight {
+            return Err(format!("The refund of booking {booking_id} is already being sent."));
+        }
+
+        let deduplicated = ledger::is_deduplicated(refund.created_at_time, now);
+        if refund.outcome_unknown {
+            if !deduplicated {
+                self.pending_refunds.remove(&booking_id);
+                return Err(format!(
+                    "The refund of booking {booking_id} may have been sent; what is left in escrow is refunded after the sale."
+                ));
+            }
+        } else {
+            if !deduplicated {
+                refund.created_at_time = now;
+            }
+            refund.amount = (refund.amount + refund.fee).saturating_sub(fee);
+            refund.fee = fee;
+            refund.to = to;
+        }
+        refund.in_flight = true;
+        Ok(refund.clone())
+    }
+
+    /// Drops a paid refund and notes it on its booking; any other one stays pending for `retry_refund`.
+    pub fn end_refund(&mut self, booking_id: u64, outcome: &TransferOutcome) {
+        let Some(refund) = self.pending_refunds.get_mut(&booking_id) else {
+            return;
+        };
+        refund.in_flight = false;
+        match outcome {
+            TransferOutcome::Paid(block_index) => {
+                let investor = refund.investor;
+                self.pending_refunds.remove(&booking_id);
+                self.set_refund_block_index(investor, booking_id, block_index.clone());
+            }
+            TransferOutcome::Rejected(e) => refund.last_error = Some(e.clone()),
+            TransferOutcome::Unknown(e) => {
+                refund.outcome_unknown = true;
+                refund.last_error = Some(e.clone());
+            }
+        }
+    }
+
+    fn check_no_refund_in_flight(&self) -> Result<(), String> {
+        if self.pending_refunds.values().any(|refund| refund.in_flight) {
+            return Err("Refunds of cancelled bookings are still being sent, try again.".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn bookings_of(&self, investor: &Principal) -> Vec<Booking> {
+        BOOKINGS.with_borrow(|bookings| {
+            bookings
+                .range((*investor, u64::MIN)..=(*investor, u64::MAX))
+                .map(|(_, booking)| booking)
+                .collect()
+        })
+    }
+
+    /// What `investor` has booked in each ledger and owes there, at the prices they booked
+    /// for, less what cancellations freed. Tokens booked before bookings were recorded
+    /// count towards `token` at `price`.
+    pub fn positions(&self, investor: &Principal, metadata: &Metadata) -> BTreeMap<Principal, Position> {
+        let booked = self.booked_tokens_of(investor);
+        if booked == 0 {
+            return BTreeMap::new();
+        }
+        let mut totals: BTreeMap<Principal, (i128, i128)> = BTreeMap::new();
+        let mut recorded = 0i128;
+        for booking in self.bookings_of(investor) {
+            let sign = if booking.kind == BookingKind::Booked { 1 } else { -1 };
+            let total = totals.entry(booking.ledger.unwrap_or(metadata.token)).or_default();
+            total.0 += sign * booking.quantity as i128;
+            total.1 += sign * booking.amount as i128;
+            recorded += sign * booking.quantity as i128;
+        }
+        let unrecorded = (booked as i128 - recorded).max(0);
+        if unrecorded > 0 {
+            let total = totals.entry(metadata.token).or_default();
+            total.0 += unrecorded;
+            total.1 += (unrecorded as f64 * metadata.price) as i128;
+        }
+
+        totals
+            .into_iter()
+            .map(|(ledger, (quantity, amount))| {
+                let position = Position {
+                    quantity: quantity.max(0) as u128,
+                    amount: amount.max(0) as u128,
+                };
+                (ledger, position)
+            })
+            .collect()
+    }
+
+    pub fn position(&self, investor: &Principal, ledger: Principal, metadata: &Metadata) -> Position {
+        self.positions(investor, metadata).remove(&ledger).unwrap_or_default()
+    }
+
+    /// What all booked tokens are worth at the prices they were booked for, in units of `token`.
+    pub fn total_raised(&self, metadata: &Metadata) -> u128 {
+        self.get_participating_investors()
+            .iter()
+            .flat_map(|investor| self.positions(investor, metadata))
+            .map(|(ledger, position)| metadata.to_token_units(ledger, position.amount))
+            .sum()
+    }
+
+    pub fn summary(&self, metadata: &Metadata) -> SaleSummary {
+        let raised = self.total_raised(metadata);
+        SaleSummary {
+            sale_status: self.sale_status.clone(),
+            total_booked_tokens: self.total_booked_tokens,
+            supply_cap: metadata.supply_cap,
+            raised,
+            min_raise: metadata.min_raise.clone(),
+            min_raise_reached: metadata.min_raise_reached(self.total_booked_tokens, raised),
+            sale_start: metadata.sale_start,
+            sale_end: metadata.sale_end,
+        }
+    }
+
+    /// Whether the sale can still be accepted or rejected.
+    pub fn is_open(&self) -> bool {
+        matches!(self.sale_status, SaleStatus::Live | SaleStatus::Closed)
+    }
+
+    /// Fixes what every investor pays and receives in each ledger, then moves the sale
+    /// to `Settling`. Does nothing if settlement has started already.
+    pub fn begin_settlement(&mut self, metadata: &Metadata, now: u64) -> Result<(), String> {
+        match self.sale_status {
+            SaleStatus::Settling => return Ok(()),
+            SaleStatus::Live | SaleStatus::Closed => {}
+            _ => return Err("Sale not live.".to_string()),
+        }
+        if !self.pulls_in_flight.is_empty() {
+            return Err("Payments for bookings are still being pulled, try again.".to_string());
+        }
+        // Excess refunds read the escrow balance, so no cancellation refund may land after them.
+        self.check_no_refund_in_flight()?;
+        let summary = self.summary(metadata);
+        if !summary.min_raise_reached {
+            return Err(format!(
+                "Minimum raise not reached: {} tokens booked, raising {}.",
+                summary.total_booked_tokens, summary.raised
+            ));
+        }
+
+        for investor in self.get_participating_investors() {
+            for (ledger, position) in self.positions(&investor, metadata) {
+                if position.quantity > 0 {
+                    settlement::insert(Settlement::new(investor, ledger, position.quantity, position.amount, now));
+                }
+            }
+        }
+        self.sale_status = SaleStatus::Settling;
+        Ok(())
+    }
+
     /// Accept the sale
     pub fn accept_sale(&mut self) {
         self.sale_status = SaleStatus::Accepted;
@@ -78,17 +522,23 @@ impl EscrowStore {
         self.sale_status = status;
     }
 
-    /// Reject the sale
-    pub fn reject_sale(&mut self) {
-        self.sale_status = SaleStatus::Rejected;
-    }
-
-    pub fn reject_sale_update_invester_booked_tokens(&mut self, invester: &Principal) {
-        self.sale_status = SaleStatus::Rejected;
-        if let Some(x) =  self.booked_tokens.get_mut(invester) {
-            *x = 0;
+    /// Rejects the sale and releases every booking, so that all escrow can be refunded.
+    pub fn reject_sale(&mut self) -> Result<(), String> {
+        if !self.is_open() {
+            return Err("Sale not live.".to_string());
         }
-       
+        // A pull that returns after the refunds would leave its payment in escrow.
+        if !self.pulls_in_flight.is_empty() {
+            return Err("Payments for bookings are still being pulled, try again.".to_string());
+        }
+        self.check_no_refund_in_flight()?;
+        self.sale_status = SaleStatus::Rejected;
+        BOOKED_TOKENS.with_borrow_mut(|booked| {
+            for investor in booked.iter().map(|(investor, _)| investor).collect::<Vec<_>>() {
+                booked.insert(investor, 0);
+            }
+        });
+        Ok(())
     }
 
     pub async fn icrc1_balance_of( token_ledger_canister_principal: Principal ,arg: Icrc1Account) -> Result<u128, String> {
@@ -103,109 +553,141 @@ impl EscrowStore {
             }
     }
 
-    pub async fn refund_from_escrow(&self, invester: &Principal, metadata: Metadata) -> Result<RefundResult, String> {
-        let icp_ledger_index = metadata.index;
-    let icp_ledger = metadata.token;
-
-    let escrow_subaccount: Subaccount = invester.into();
-    let escrow_account_id = AccountIdentifier::from_principal(ic_cdk::id(), Some(escrow_subaccount));
-    // let args = index_canister::GetAccountTransactionsArgs {
-    //     account: Account {
-    //         owner: ic_cdk::id(),
-    //         subaccount: Some(escrow_subaccount.to_vec()),
-    //     },
-    //     start: None,
-    //     max_results: Nat::from(50u64),
-    // };
-
-   
-    let index_query_result = index_canister::Service(icp_ledger_index).get_account_transactions(index_canister::GetAccountTransactionsArgs {
-        account: Account {
-            owner: ic_cdk::id(),
-            subaccount: Some(escrow_subaccount.to_vec()),
-        },
-        start: None,
-        max_results: Nat::from(50u64),
-    }).await.map_err(|(c,e)| format!("Failed to get account transactions canister error {c:?} {e} ", ))?;
-
-    // Query transactions
-    // let (index_query_result,): (Result<GetTransactions, GetTransactionsError>, ) = call(icp_ledger_index, "get_account_transactions", (&GetAccountTransactionsArgs {
-    //     account: Icrc1Account {
-    //         owner: ic_cdk::id(),
-    //         subaccount: Some(escrow_subaccount.to_vec()),
-    //     },
-    //     start: None,
-    //     max_results: Nat::from(5u64),
-    // }, )).await.map_err(|(c,e)| format!("Failed to get account transactions: {c:?} {e} "))?;
-
-    let index_query_result = match  index_query_result.0 {
-        index_canister::GetAccountIdentifierTransactionsResult::Ok(get_transactions) => get_transactions,
-        index_canister::GetAccountIdentifierTransactionsResult::Err(f) => return  Err(format!("Failed to get account transactions from index canister {}", f.message)),
-    };
-
-    // Validate query result
-    let escrow_balance = index_query_result.balance;
-    let txns = index_query_result.transactions.clone();
-    let refund_account_id = index_query_result
-        .transactions
-        .into_iter()
-        .filter_map(|txn|{ match txn.transaction.operation {
-            index_canister::Operation::Transfer { to, fee, from, amount, spender }   => {
-                if to == escrow_account_id.to_hex()  {
-                    Some(from.to_string())
-                } else {
-                    None
-                }
+    /// Pulls `amount` from `from` into the investor's escrow subaccount. The booking's id and
+    /// time make the transfer deduplicated, and a duplicate reported by the ledger counts as paid.
+    pub async fn pull_payment(ledger: Principal, from: LedgerAccount, escrow_subaccount: Subaccount, amount: u128, fee: u128, booking: &Booking) -> Result<Nat, String> {
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to: LedgerAccount {
+                owner: ic_cdk::id(),
+                subaccount: Some(escrow_subaccount.0),
             },
-            _ => None
-        }})
-        .collect::<Vec<_>>();
-
-    // let refund_account_id = transactions
-    //     .iter()
-    //     .find(|txn| match txn.transaction.operation {
-    //         index_canister::Operation::Transfer{ to, fee, from, amount, spender } => 
-            
-    //     } as Operation::Trabsfer  AccountIdentifier::from_principal(txn.transaction.to.owner, txn.to.subaccount.clone().map(|f| Subaccount::try_from(f.as_slice()).ok()).flatten())  == escrow_account_id)
-    //     .map(|txn| txn.from.clone());
-    
-
-    let refund_account_id = match refund_account_id.first() {
-        Some(account)  => account.clone(),
-        None => return Result::Err(format!("Txn: {refund_account_id:?} not found in index: {:?} for sent to : {:?}",txns,  escrow_account_id.to_hex())),
-    };
-
-    // Calculate refund amount
-    const TRANSFER_FEE: u64 = 10_000;
-    let refund_amount = (escrow_balance).saturating_sub(TRANSFER_FEE);
-    if refund_amount == 0 {
-        return Result::Ok(RefundResult {
-            to: refund_account_id.into(),
-            amount: 0,
-        });
+            amount: amount.into(),
+            fee: Some(fee.into()),
+            memo: Some(booking.id.into()),
+            created_at_time: Some(booking.booked_at),
+        };
+
+        match ledger::transfer_from(ledger, args).await {
+            TransferOutcome::Paid(block_index) => Ok(block_index),
+            TransferOutcome::Rejected(e) | TransferOutcome::Unknown(e) => Err(format!("Failed to pull the payment: {e}")),
+        }
     }
 
-    // Transfer funds   
-
+    /// Registers where `investor`'s refunds are sent.
+    pub fn set_refund_account(&mut self, investor: Principal, account: Icrc1Account) -> Result<(), String> {
+        if account.owner == Principal::anonymous() {
+            return Err("Refunds cannot be sent to the anonymous principal.".to_string());
+        }
+        if account.subaccount.as_ref().is_some_and(|subaccount| subaccount.len() != 32) {
+            return Err("Subaccounts must be 32 bytes long.".to_string());
+        }
+        self.refund_accounts.insert(investor, account);
+        Ok(())
+    }
 
-             let _result =   transfer(icp_ledger, TransferArgs { memo: Memo(0), amount: Tokens::from_e8s(refund_amount), fee:DEFAULT_FEE, from_subaccount: Some(ic_ledger_types::Subaccount(escrow_subaccount.0)), to: ic_ledger_types::AccountIdentifier::from_hex(&refund_account_id)?, created_at_time: None }).await.map_err(|(c,e)| format!("Failed to call transfer: {c:?} {e} "))?.map_err(|f| format!("Failed to transfer: {f} "))?;
+    /// The registered refund account of `investor`, or their principal's default account.
+    pub fn refund_account(&self, investor: &Principal) -> Icrc1Account {
+        self.refund_accounts.get(investor).cloned().unwrap_or(Icrc1Account {
+            owner: *investor,
+            subaccount: None,
+        })
+    }
 
+    /// Sends `invester`'s escrow in `currency` beyond `keep`, less the fee, to their refund account.
+    /// Investors who never registered one are refunded to the account their deposits came from
+    /// when the ledger's ICRC index shows a single one, and to their principal's default account
+    /// otherwise. The ICP index only reports account identifiers, which ICRC-1 cannot pay, so ICP
+    /// is always refunded to the refund account.
+    pub async fn refund_from_escrow(&self, invester: &Principal, currency: &AcceptedLedger, keep: u128) -> Result<RefundResult, String> {
+        let fee = ledger::ledger_info(currency.ledger).await?.fee;
+        let escrow_subaccount: Subaccount = invester.into();
+        let escrow_account = Icrc1Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow_subaccount.to_vec()),
+        };
+        let escrow_balance = Self::icrc1_balance_of(currency.ledger, escrow_account).await?;
+        let refund_amount = escrow_balance.saturating_sub(keep).saturating_sub(fee);
+
+        let depositor = if self.refund_accounts.contains_key(invester) || currency.ledger == MAINNET_LEDGER_CANISTER_ID {
+            None
+        } else {
+            match Self::depositors_from_index(currency.index, escrow_subaccount).await {
+                Ok(depositors) if depositors.len() == 1 => depositors.into_iter().next(),
+                // Without the index the refund account is used, as for any other investor.
+                Ok(_) | Err(_) => None,
+            }
+        };
+
+        let to = depositor.unwrap_or_else(|| ledger_account(&self.refund_account(invester)));
+        if refund_amount > 0 {
+            let args = TransferArg {
+                from_subaccount: Some(escrow_subaccount.0),
+                to,
+                fee: Some(fee.into()),
+                created_at_time: Some(ic_cdk::api::time()),
+                memo: Some(Memo::from(invester.as_slice().to_vec())),
+                amount: refund_amount.into(),
+            };
+            // The amount is read from the balance again before any retry, so nothing is sent twice.
+            if let TransferOutcome::Rejected(e) | TransferOutcome::Unknown(e) = ledger::transfer(currency.ledger, args).await {
+                return Err(e);
+            }
+        }
+        Ok(RefundResult { ledger: currency.ledger, to: to.to_string(), amount: refund_amount })
+    }
 
-    // let _ : (Result<u64, TransferError1>,) = call(icp_ledger, "transfer", (GetAccountTransactionsArgs {
-    //     account: Icrc1Account {
-    //         owner: ic_cdk::id(),
-    //         subaccount: Some(escrow_subaccount.to_vec()),
-    //     },
-    //     start: None,
-    //     max_results: Nat::from(5 as u64),
-    // }, )).await.map_err(|(c,e)| format!("Failed to transfer: {c:?} {e} "))?;
+    /// Every account that sent funds to the escrow subaccount, paging through its whole history
+    /// in the ledger's ICRC index.
+    async fn depositors_from_index(index: Principal, escrow_subaccount: Subaccount) -> Result<BTreeSet<LedgerAccount>, String> {
+        const PAGE_SIZE: u64 = 100;
+        let escrow_account = Icrc1Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow_subaccount.to_vec()),
+        };
+
+        let mut depositors = BTreeSet::new();
+        let mut start: Option<Nat> = None;
+        loop {
+            let args = GetAccountTransactionsArgs {
+                account: escrow_account.clone(),
+                start: start.clone(),
+                max_results: Nat::from(PAGE_SIZE),
+            };
+            let (result,): (GetTransactionsResult,) = call(index, "get_account_transactions", (args,))
+                .await
+                .map_err(|(c, e)| format!("Failed to get account transactions canister error {c:?} {e} "))?;
+            let page = match result {
+                GetTransactionsResult::Ok(page) => page,
+                GetTransactionsResult::Err(f) => {
+                    return Err(format!("Failed to get account transactions from index canister {}", f.message))
+                }
+            };
+
+            for txn in &page.transactions {
+                if let Some(transfer) = &txn.transaction.transfer {
+                    let to = ledger_account(&transfer.to);
+                    if to == ledger_account(&escrow_account) {
+                        depositors.insert(ledger_account(&transfer.from));
+                    }
+                }
+            }
 
+            // Pages run from the newest transaction to the oldest, starting after `start`.
+            match page.transactions.last() {
+                Some(last) if page.oldest_tx_id.as_ref() != Some(&last.id) => start = Some(last.id.clone()),
+                _ => return Ok(depositors),
+            }
+        }
+    }
+}
 
-    Ok(RefundResult {
-        to: refund_account_id.into(),
-        amount: refund_amount,
-    })
-       
+/// The ledger's form of an account whose subaccount was checked on registration.
+pub fn ledger_account(account: &Icrc1Account) -> LedgerAccount {
+    LedgerAccount {
+        owner: account.owner,
+        subaccount: account.subaccount.as_ref().and_then(|subaccount| subaccount.as_slice().try_into().ok()),
     }
 }
 
@@ -218,10 +700,11 @@ pub enum TransferError1 {
   InsufficientFunds{ balance: u64 },
 }
 
-#[derive(CandidType, Deserialize,)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct RefundResult {
+    pub ledger: Principal,
     pub to: String,
-    pub amount: u64,
+    pub amount: u128,
 }
 
 #[derive( Clone, CandidType, Deserialize)]
@@ -233,9 +716,9 @@ struct GetAccountTransactionsArgs {
 
 #[derive(CandidType, Deserialize)]
 pub struct GetTransactions {
-  pub balance: u64,
+  pub balance: Nat,
   pub transactions: Vec<TransactionWithId>,
-  pub oldest_tx_id: Option<u64>,
+  pub oldest_tx_id: Option<Nat>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -243,9 +726,15 @@ pub struct GetTransactionsError {
   pub message : String,
 }
 
+#[derive(CandidType, Deserialize)]
+enum GetTransactionsResult {
+  Ok(GetTransactions),
+  Err(GetTransactionsError),
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct TransactionWithId {
-  pub id: u64,
+  pub id: Nat,
   pub transaction: Transaction,
 }
 
@@ -297,4 +786,164 @@ pub struct Transfer {
   pub created_at_time: Option<u64>,
   pub amount: candid::Nat,
   pub spender: Option<Icrc1Account>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::super::metadata::tests::metadata;
+    use super::*;
+
+    #[test]
+    fn test_cancellations_reduce_what_is_owed() {
+        let investor = Principal::from_slice(&[1]);
+        let token = Principal::from_slice(&[10]);
+        let metadata = metadata(token, 100.0);
+        let mut escrow = EscrowStore::default();
+        // Booked before bookings were recorded, at the base price of 100.
+        escrow.book_tokens(investor, 4);
+        escrow.record_booking(investor, token, 2, 300, 0);
+        assert_eq!(escrow.position(&investor, token, &metadata), Position { quantity: 6, amount: 700 });
+
+        let cancelled = escrow.cancel_booking(investor, token, 3, &metadata, 1).unwrap();
+        assert_eq!(cancelled.amount, 350);
+        assert_eq!(escrow.booked_tokens_of(&investor), 3);
+        assert_eq!(escrow.total_booked_tokens, 3);
+        assert_eq!(escrow.position(&investor, token, &metadata).amount, 350);
+
+        assert!(escrow.cancel_booking(investor, token, 4, &metadata, 2).is_err());
+        escrow.cancel_booking(investor, token, 3, &metadata, 2).unwrap();
+        assert_eq!(escrow.position(&investor, token, &metadata).amount, 0);
+        assert_eq!(escrow.bookings_of(&investor).len(), 3);
+    }
+
+    #[test]
+    fn test_positions_are_kept_per_ledger() {
+        let investor = Principal::from_slice(&[4]);
+        let (token, ckusdc) = (Principal::from_slice(&[10]), Principal::from_slice(&[11]));
+        let mut metadata = metadata(token, 100.0);
+        metadata.accepted_ledgers = Some(vec![AcceptedLedger { ledger: ckusdc, price: 50.0, index: token }]);
+        let mut escrow = EscrowStore::default();
+        escrow.record_booking(investor, token, 1, 100, 0);
+        escrow.record_booking(investor, ckusdc, 2, 100, 0);
+
+        assert!(escrow.cancel_booking(investor, token, 2, &metadata, 1).is_err());
+        assert_eq!(escrow.position(&investor, ckusdc, &metadata), Position { quantity: 2, amount: 100 });
+        // 100 units of ckUSDC buy as many tokens as 200 units of the sale token.
+        assert_eq!(escrow.total_raised(&metadata), 300);
+    }
+
+    #[test]
+    fn test_reconciliation_keeps_a_fee_per_token_back() {
+        let (investor, token) = (Principal::from_slice(&[5]), Principal::from_slice(&[10]));
+        let position = Position { quantity: 2, amount: 200 };
+
+        let short = ReconciliationRow::new(investor, token, position, 205, 10);
+        assert_eq!((short.fee_reserve, short.excess, short.shortfall), (20, 0, 15));
+        let over = ReconciliationRow::new(investor, token, position, 250, 10);
+        assert_eq!((over.excess, over.shortfall), (30, 0));
+
+        let mut total = ReconciliationTotal::new(token);
+        total.add(&short);
+        total.add(&over);
+        assert_eq!((total.escrow_balance, total.amount_owed, total.excess, total.shortfall), (455, 400, 30, 15));
+    }
+
+    #[test]
+    fn test_bookings_being_paid_cannot_be_cancelled() {
+        let investor = Principal::from_slice(&[6]);
+        let token = Principal::from_slice(&[10]);
+        let metadata = metadata(token, 100.0);
+        let mut escrow = EscrowStore::default();
+        escrow.begin_pull(investor).unwrap();
+        let pulled = escrow.record_booking(investor, token, 2, 200, 0);
+
+        assert!(escrow.begin_pull(investor).is_err());
+        assert!(escrow.cancel_booking(investor, token, 2, &metadata, 1).is_err());
+        assert!(escrow.begin_settlement(&metadata, 1).is_err());
+
+        escrow.revert_booking(investor, &pulled);
+        escrow.end_pull(&investor);
+        assert_eq!(escrow.total_booked_tokens, 0);
+        assert!(escrow.bookings_of(&investor).is_empty());
+        assert!(escrow.cancel_booking(investor, token, 1, &metadata, 2).is_err());
+    }
+
+    #[test]
+    fn test_refund_account_defaults_to_the_principal() {
+        let investor = Principal::from_slice(&[1]);
+        let mut escrow = EscrowStore::default();
+        assert_eq!(escrow.refund_account(&investor).owner, investor);
+
+        let short = Icrc1Account { owner: investor, subaccount: Some(vec![1; 8]) };
+        assert!(escrow.set_refund_account(investor, short).is_err());
+        let exchange = Icrc1Account { owner: Principal::from_slice(&[2]), subaccount: Some(vec![1; 32]) };
+        escrow.set_refund_account(investor, exchange).unwrap();
+        let account = ledger_account(&escrow.refund_account(&investor));
+        assert_eq!(account.owner, Principal::from_slice(&[2]));
+        assert_eq!(account.subaccount, Some([1; 32]));
+    }
+    #[test]
+    fn test_reverted_booking_frees_its_tokens() {
+        let investor = Principal::from_slice(&[3]);
+        let mut escrow = EscrowStore::default();
+        let token = Principal::from_slice(&[10]);
+        escrow.record_booking(investor, token, 1, 100, 0);
+        let pulled = escrow.record_booking(investor, token, 2, 200, 1);
+
+        escrow.revert_booking(investor, &pulled);
+        assert_eq!(escrow.booked_tokens_of(&investor), 1);
+        assert_eq!(escrow.total_booked_tokens, 1);
+        assert_eq!(escrow.position(&investor, token, &metadata(token, 100.0)).amount, 100);
+        assert_eq!(escrow.bookings_of(&investor).len(), 1);
+    }
+
+    #[test]
+    fn test_rejected_sale_releases_every_booking() {
+        let investor = Principal::from_slice(&[7]);
+        let mut escrow = EscrowStore { sale_status: SaleStatus::Closed, ..Default::default() };
+        escrow.record_booking(investor, Principal::from_slice(&[10]), 2, 200, 0);
+        escrow.begin_pull(investor).unwrap();
+        assert!(escrow.reject_sale().is_err());
+        escrow.end_pull(&investor);
+
+        escrow.reject_sale().unwrap();
+        assert_eq!(escrow.sale_status, SaleStatus::Rejected);
+        assert_eq!(escrow.booked_tokens_of(&investor), 0);
+        assert_eq!(escrow.get_participating_investors(), vec![investor]);
+        assert!(escrow.reject_sale().is_err());
+    }
+
+    #[test]
+    fn test_failed_cancellation_refunds_are_sent_again_unchanged() {
+        let investor = Principal::from_slice(&[8]);
+        let token = Principal::from_slice(&[10]);
+        let metadata = metadata(token, 100.0);
+        let mut escrow = EscrowStore::default();
+        escrow.record_booking(investor, token, 2, 200, 0);
+        let cancelled = escrow.cancel_booking(investor, token, 2, &metadata, 100).unwrap();
+
+        escrow.begin_refund(investor, &cancelled, 210, 10, token);
+        assert!(escrow.check_no_refund_in_flight().is_err());
+        escrow.end_refund(cancelled.id, &TransferOutcome::Unknown("call failed".to_string()));
+
+        // The ledger may hold the first attempt, so the retry is the same transfer.
+        let retry = escrow.retry_refund(investor, cancelled.id, 20, 200).unwrap();
+        assert_eq!((retry.amount, retry.fee, retry.created_at_time), (210, 10, 100));
+        assert!(escrow.retry_refund(investor, cancelled.id, 20, 200).is_err());
+        escrow.end_refund(cancelled.id, &TransferOutcome::Rejected("bad fee".to_string()));
+
+        // Past the window it is left to the excess refund after the sale.
+        assert!(escrow.retry_refund(investor, cancelled.id, 20, 100 + ledger::LEDGER_TX_WINDOW + 1).is_err());
+        assert!(escrow.pending_refunds.is_empty());
+
+        escrow.record_booking(investor, token, 1, 100, 300);
+        let cancelled = escrow.cancel_booking(investor, token, 1, &metadata, 400).unwrap();
+        escrow.begin_refund(investor, &cancelled, 100, 10, token);
+        escrow.end_refund(cancelled.id, &TransferOutcome::Rejected("insufficient funds".to_string()));
+        let retry = escrow.retry_refund(investor, cancelled.id, 20, 500).unwrap();
+        assert_eq!((retry.amount, retry.fee, retry.created_at_time), (90, 20, 400));
+        escrow.end_refund(cancelled.id, &TransferOutcome::Paid(Nat::from(4u64)));
+        assert!(escrow.pending_refunds.is_empty());
+        let refunded = escrow.bookings_of(&investor).into_iter().find(|booking| booking.id == cancelled.id).unwrap();
+        assert_eq!(refunded.kind, BookingKind::Cancelled { refund_block_index: Some(Nat::from(4u64)) });
+    }
+}
@@ -0,0 +1,121 @@
+use std::cell::Cell;
+use std::ops::Bound;
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::STATE;
+
+use super::escrow::RefundResult;
+use super::memory::EXCESS_REFUNDS;
+
+thread_local! {
+    static IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The last escrow refunded to one investor in one ledger after the sale, or why it failed.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExcessRefund {
+    pub investor: Principal,
+    pub ledger: Principal,
+    /// What reached the investor, after the fee, in ledger units.
+    pub amount: u128,
+    pub to: Option<String>,
+    pub refunded_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+pub fn insert(refund: ExcessRefund) {
+    EXCESS_REFUNDS.with_borrow_mut(|refunds| refunds.insert((refund.investor, refund.ledger), refund));
+}
+
+pub fn get(key: &(Principal, Principal)) -> Option<ExcessRefund> {
+    EXCESS_REFUNDS.with_borrow(|refunds| refunds.get(key))
+}
+
+/// Up to `take` refunds ordered by investor and ledger, starting after `prev`.
+pub fn excess_refunds(prev: Option<(Principal, Principal)>, take: usize) -> Vec<ExcessRefund> {
+    let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+    EXCESS_REFUNDS.with_borrow(|refunds| {
+        refunds
+            .range((start, Bound::Unbounded))
+            .take(take)
+            .map(|(_, refund)| refund)
+            .collect()
+    })
+}
+
+/// Records the outcome of a refund. A refund that found nothing to send keeps the amount
+/// of the last one, and is not recorded for investors who were never refunded.
+pub fn record(investor: Principal, ledger: Principal, result: Result<RefundResult, String>, now: u64) {
+    let previous = get(&(investor, ledger));
+    if previous.is_none() && matches!(&result, Ok(result) if result.amount == 0) {
+        return;
+    }
+    let mut refund = previous.unwrap_or(ExcessRefund {
+        investor,
+        ledger,
+        amount: 0,
+        to: None,
+        refunded_at: None,
+        last_error: None,
+    });
+    match result {
+        Ok(result) if result.amount == 0 => refund.last_error = None,
+        Ok(result) => {
+            refund.amount = result.amount;
+            refund.to = Some(result.to);
+            refund.refunded_at = Some(now);
+            refund.last_error = None;
+        }
+        Err(e) => refund.last_error = Some(e),
+    }
+    insert(refund);
+}
+
+pub fn lock() -> Result<(), String> {
+    if IN_PROGRESS.replace(true) {
+        return Err("Excess escrow is already being refunded.".to_string());
+    }
+    Ok(())
+}
+
+pub fn unlock() {
+    IN_PROGRESS.set(false);
+}
+
+/// Refunds the excess escrow of every investor in the background, once the sale is accepted.
+pub fn schedule_refunds() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let state = STATE.with_borrow(|f| f.clone());
+            // Each failure is kept on its refund's `last_error` for `refund_all_excess` to retry.
+            let _ = state.refund_all_excess().await;
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_keep_the_last_refund() {
+        let (investor, ledger) = (Principal::from_slice(&[1]), Principal::from_slice(&[10]));
+        let refunded = |amount| Ok(RefundResult { ledger, to: "account".to_string(), amount });
+
+        record(investor, ledger, refunded(0), 1);
+        assert!(get(&(investor, ledger)).is_none());
+
+        record(investor, ledger, refunded(90), 2);
+        record(investor, ledger, Err("unavailable".to_string()), 3);
+        let refund = get(&(investor, ledger)).unwrap();
+        assert_eq!((refund.amount, refund.refunded_at), (90, Some(2)));
+        assert_eq!(refund.last_error.as_deref(), Some("unavailable"));
+
+        record(investor, ledger, refunded(0), 4);
+        let refund = get(&(investor, ledger)).unwrap();
+        assert_eq!((refund.amount, refund.last_error), (90, None));
+    }
+}
@@ -0,0 +1,28 @@
+use icrc_ledger_types::icrc3::archive::{GetArchivesArgs, ICRC3ArchiveInfo};
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType};
+
+use crate::STATE;
+
+use super::transactions::TxnIndexStore;
+
+/// Returns at most `max_query_batch_size` blocks per call.
+#[ic_cdk_macros::query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    STATE.with_borrow(|state| state.transactions.get_blocks(args, state.settings.max_query_batch_size as u64))
+}
+
+#[ic_cdk_macros::query]
+pub fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
+    STATE.with_borrow(|state| state.transactions.tip_certificate())
+}
+
+#[ic_cdk_macros::query]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    TxnIndexStore::supported_block_types()
+}
+
+/// The log is never archived, so there are no archive canisters to report.
+#[ic_cdk_macros::query]
+pub fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ICRC3ArchiveInfo> {
+    vec![]
+}
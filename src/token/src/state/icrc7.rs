@@ -4,49 +4,49 @@ use crate::STATE;
 
 #[ic_cdk_macros::query]
 pub fn icrc7_max_query_batch_size() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.max_query_batch_size.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_max_update_batch_size() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.max_update_batch_size.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_max_default_take_value() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.max_default_take_value.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_max_take_value() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.max_take_value.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_max_memo_size() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.max_memo_size.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_atomic_batch_transfers() -> Option<bool> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.atomic_batch_transfers))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_tx_window() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.tx_window.into()))
 }
 
 #[ic_cdk_macros::query]
 pub fn icrc7_permitted_drift() -> Option<Nat> {
-    None
+    STATE.with(|store| Some(store.borrow().settings.permitted_drift.into()))
 }
 
 // Functions
 #[ic_cdk_macros::query]
 pub fn icrc7_symbol() -> String {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return String::new();
         }
@@ -58,7 +58,7 @@ pub fn icrc7_symbol() -> String {
 #[ic_cdk_macros::query]
 pub fn icrc7_name() -> String {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return String::new();
         }
@@ -70,7 +70,7 @@ pub fn icrc7_name() -> String {
 #[ic_cdk_macros::query]
 pub fn icrc7_description() -> Option<String> {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return None;
         }
@@ -82,7 +82,7 @@ pub fn icrc7_description() -> Option<String> {
 #[ic_cdk_macros::query]
 pub fn icrc7_logo() -> Option<String> {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return None;
         }
@@ -94,7 +94,7 @@ pub fn icrc7_logo() -> Option<String> {
 #[ic_cdk_macros::query]
 pub fn icrc7_total_supply() -> Nat {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return Nat::from(0u8);
         }
@@ -105,7 +105,7 @@ pub fn icrc7_total_supply() -> Nat {
 #[ic_cdk_macros::query]
 pub fn icrc7_supply_cap() -> Option<Nat> {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return None;
         }
@@ -126,7 +126,7 @@ pub enum MetadataValue {
 #[ic_cdk_macros::query]
 pub fn icrc7_collection_metadata() -> ICRC7MetadataQueryResult {
     STATE.with(|store| {
-        let metadata = store.borrow().metadata.clone();
+        let metadata = store.borrow().metadata();
         if metadata.is_none() {
             return Vec::new();
         }
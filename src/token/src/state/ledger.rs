@@ -0,0 +1,119 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+
+use crate::STATE;
+
+/// How long ICRC-1 ledgers deduplicate a transfer by its `created_at_time`, and how far it may be
+/// off their clock. The standard does not expose either; these are the ICP and ICRC-1 ledgers' own.
+pub const LEDGER_TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const LEDGER_PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+/// How a transfer sent with a fixed `created_at_time` and memo ended.
+#[derive(Debug, PartialEq)]
+pub enum TransferOutcome {
+    Paid(Nat),
+    /// The ledger refused this attempt, so it moved nothing.
+    Rejected(String),
+    /// The call failed, or the ledger could not tell it from an earlier attempt, so the
+    /// transfer may or may not have happened.
+    Unknown(String),
+}
+
+impl From<CallResult<(Result<Nat, TransferError>,)>> for TransferOutcome {
+    fn from(result: CallResult<(Result<Nat, TransferError>,)>) -> Self {
+        match result {
+            Ok((Ok(block_index),)) => Self::Paid(block_index),
+            Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Self::Paid(duplicate_of),
+            Ok((Err(e @ (TransferError::TooOld | TransferError::CreatedInFuture { .. })),)) => {
+                Self::Unknown(format!("Ledger transfer error: {e}"))
+            }
+            Ok((Err(e),)) => Self::Rejected(format!("Ledger transfer error: {e}")),
+            Err((code, message)) => Self::Unknown(format!("Failed to call ledger: {code:?} {message}")),
+        }
+    }
+}
+
+impl From<CallResult<(Result<Nat, TransferFromError>,)>> for TransferOutcome {
+    fn from(result: CallResult<(Result<Nat, TransferFromError>,)>) -> Self {
+        match result {
+            Ok((Ok(block_index),)) => Self::Paid(block_index),
+            Ok((Err(TransferFromError::Duplicate { duplicate_of }),)) => Self::Paid(duplicate_of),
+            Ok((Err(e @ (TransferFromError::TooOld | TransferFromError::CreatedInFuture { .. })),)) => {
+                Self::Unknown(format!("Ledger transfer_from error: {e}"))
+            }
+            Ok((Err(e),)) => Self::Rejected(format!("Ledger transfer_from error: {e}")),
+            Err((code, message)) => Self::Unknown(format!("Failed to call ledger: {code:?} {message}")),
+        }
+    }
+}
+
+/// Sends `args` to `ledger`, caching the fee the ledger asks for if it refuses the one sent.
+pub async fn transfer(ledger: Principal, args: TransferArg) -> TransferOutcome {
+    let result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+    if let Ok((Err(TransferError::BadFee { expected_fee }),)) = &result {
+        update_fee(ledger, expected_fee);
+    }
+    result.into()
+}
+
+/// Like `transfer`, for `icrc2_transfer_from`.
+pub async fn transfer_from(ledger: Principal, args: TransferFromArgs) -> TransferOutcome {
+    let result: CallResult<(Result<Nat, TransferFromError>,)> = ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+    if let Ok((Err(TransferFromError::BadFee { expected_fee }),)) = &result {
+        update_fee(ledger, expected_fee);
+    }
+    result.into()
+}
+
+/// Whether a transfer sent again with `created_at_time` is still checked against the earlier
+/// attempts. The drift is left as a margin for the ledger's clock.
+pub fn is_deduplicated(created_at_time: u64, now: u64) -> bool {
+    created_at_time.saturating_add(LEDGER_TX_WINDOW) >= now
+}
+
+/// A transfer whose outcome is unknown may only be taken as not made once the ledger can no
+/// longer accept it, allowing for the drift of both clocks.
+pub fn check_expired(created_at_time: u64, now: u64) -> Result<(), String> {
+    let expires_at = created_at_time.saturating_add(LEDGER_TX_WINDOW + 2 * LEDGER_PERMITTED_DRIFT);
+    if expires_at >= now {
+        return Err(format!("The ledger may still accept this transfer until {expires_at}."));
+    }
+    Ok(())
+}
+
+/// What an accepted ledger charges, as last read from it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerInfo {
+    pub ledger: Principal,
+    /// The fee of every transfer, in ledger units.
+    pub fee: u128,
+}
+
+/// The cached info for `ledger`, read from the ledger on first use.
+pub async fn ledger_info(ledger: Principal) -> Result<LedgerInfo, String> {
+    let cached = STATE.with_borrow(|f| f.ledger_info.get(&ledger).cloned());
+    match cached {
+        Some(info) => Ok(info),
+        None => refresh(ledger).await,
+    }
+}
+
+/// Reads the fee of `ledger` and caches it.
+pub async fn refresh(ledger: Principal) -> Result<LedgerInfo, String> {
+    let (fee,): (u128,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Failed to get the ledger fee: {code:?} {message}"))?;
+
+    let info = LedgerInfo { ledger, fee };
+    STATE.with_borrow_mut(|f| f.ledger_info.insert(ledger, info.clone()));
+    Ok(info)
+}
+
+/// Caches the fee a ledger asked for when it refused the one sent.
+fn update_fee(ledger: Principal, expected_fee: &Nat) {
+    if let Ok(fee) = u128::try_from(expected_fee.0.clone()) {
+        STATE.with_borrow_mut(|f| f.ledger_info.insert(ledger, LedgerInfo { ledger, fee }));
+    }
+}
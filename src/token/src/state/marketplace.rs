@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use serde::Serialize;
+
+use super::ledger::{self, TransferOutcome};
+use super::escrow::ledger_account;
+use super::models::Icrc1Account;
+
+/// A token its holder offers at a fixed price, paid in `ledger` straight to `seller`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Listing {
+    pub token_id: u32,
+    pub seller: Icrc1Account,
+    /// In units of `ledger`; the buyer pays the ledger fee on top.
+    pub price: u128,
+    pub ledger: Principal,
+    pub listed_at: u64,
+}
+
+/// A buyer's payment for a listing. It is sent again with the same `created_at_time`,
+/// fee and memo while the ledger deduplicates it, and after that waits for `resolve_purchase`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Purchase {
+    pub buyer: Principal,
+    pub fee: u128,
+    pub created_at_time: u64,
+    /// Set while a `buy` call waits for the ledger.
+    pub in_flight: bool,
+    /// An attempt did not return, so the seller may have been paid and the purchase only
+    /// ends when a payment goes through or `resolve_purchase` settles it.
+    pub outcome_unknown: bool,
+}
+
+/// Fixed-price listings, kept next to `TokenState`. A listed token cannot be moved
+/// by `icrc7_transfer` or `icrc37_transfer_from`, nor burned, until it is sold or delisted.
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct MarketplaceStore {
+    /// token id -> listing
+    pub listings: BTreeMap<u32, Listing>,
+    /// token id -> the purchase being paid; the listing cannot change until it ends.
+    pub purchases: BTreeMap<u32, Purchase>,
+}
+
+impl MarketplaceStore {
+    /// Lists the token, or changes the price and ledger of its listing.
+    pub fn list(&mut self, listing: Listing) -> Result<(), String> {
+        if listing.price == 0 {
+            return Err("The price must be greater than 0.".to_string());
+        }
+        self.check_not_purchasing(listing.token_id)?;
+        self.listings.insert(listing.token_id, listing);
+        Ok(())
+    }
+
+    pub fn delist(&mut self, token_id: u32) -> Result<Listing, String> {
+        self.check_not_purchasing(token_id)?;
+        self.listings
+            .remove(&token_id)
+            .ok_or(format!("Token {token_id} is not listed."))
+    }
+
+    /// Drops the listing once the token has moved.
+    pub fn clear(&mut self, token_id: u32) {
+        self.listings.remove(&token_id);
+        self.purchases.remove(&token_id);
+    }
+
+    pub fn get(&self, token_id: u32) -> Option<&Listing> {
+        self.listings.get(&token_id)
+    }
+
+    pub fn is_listed(&self, token_id: u32) -> bool {
+        self.listings.contains_key(&token_id)
+    }
+
+    /// Up to `take` listings ordered by token id, starting after `prev`.
+    pub fn listings(&self, prev: Option<u32>, take: usize) -> Vec<Listing> {
+        let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+        self.listings
+            .range((start, Bound::Unbounded))
+            .take(take)
+            .map(|(_, listing)| listing.clone())
+            .collect()
+    }
+
+    /// Reserves the listing for `buyer` while their payment is made. A purchase whose payment
+    /// did not return is taken up again by the same buyer, unchanged, while the ledger
+    /// deduplicates it.
+    pub fn begin_purchase(&mut self, token_id: u32, buyer: Principal, fee: u128, now: u64) -> Result<(Listing, Purchase), String> {
+        let listing = self
+            .listings
+            .get(&token_id)
+            .cloned()
+            .ok_or(format!("Token {token_id} is not listed."))?;
+
+        let purchase = self.purchases.entry(token_id).or_insert(Purchase {
+            buyer,
+            fee,
+            created_at_time: now,
+            in_flight: false,
+            outcome_unknown: false,
+        });
+        if purchase.buyer != buyer || purchase.in_flight {
+            return Err(format!("Token {token_id} is being bought."));
+        }
+        // Past the ledger's deduplication window a retry could pay the seller twice.
+        if !ledger::is_deduplicated(purchase.created_at_time, now) {
+            return Err(format!(
+                "The payment for token {token_id} may have been made; it waits for the collection owner to resolve it."
+            ));
+        }
+        purchase.in_flight = true;
+        Ok((listing, purchase.clone()))
+    }
+
+    /// Ends the purchase once its payment is made or refused, leaving the listing for sale again
+    /// after a refusal. A purchase that may have been paid by an earlier attempt stays with its
+    /// buyer however this one ended.
+    pub fn end_payment(&mut self, token_id: u32, outcome: &TransferOutcome) {
+        let Some(purchase) = self.purchases.get_mut(&token_id) else {
+            return;
+        };
+        purchase.in_flight = false;
+        match outcome {
+            TransferOutcome::Unknown(_) => purchase.outcome_unknown = true,
+            TransferOutcome::Rejected(_) if purchase.outcome_unknown => {}
+            TransferOutcome::Paid(_) | TransferOutcome::Rejected(_) => {
+                self.purchases.remove(&token_id);
+            }
+        }
+    }
+
+    /// Ends a purchase that may have been paid, as found on the ledger: paid in `block_index`,
+    /// so the token goes to the buyer, or not paid, once the ledger can no longer accept it.
+    pub fn resolve_purchase(&mut self, token_id: u32, block_index: &Option<Nat>, now: u64) -> Result<Purchase, String> {
+        let purchase = self
+            .purchases
+            .get(&token_id)
+            .filter(|purchase| purchase.outcome_unknown && !purchase.in_flight)
+            .cloned()
+            .ok_or(format!("No payment for token {token_id} is waiting to be resolved."))?;
+        if block_index.is_none() {
+            ledger::check_expired(purchase.created_at_time, now)?;
+        }
+        self.purchases.remove(&token_id);
+        Ok(purchase)
+    }
+
+    /// Purchases being paid or waiting to be resolved, with their token id.
+    pub fn purchases(&self) -> Vec<(u32, Purchase)> {
+        self.purchases
+            .iter()
+            .map(|(token_id, purchase)| (*token_id, purchase.clone()))
+            .collect()
+    }
+
+    fn check_not_purchasing(&self, token_id: u32) -> Result<(), String> {
+        if self.purchases.contains_key(&token_id) {
+            return Err(format!("Token {token_id} is being bought."));
+        }
+        Ok(())
+    }
+}
+
+/// Pays the seller from the buyer's default account with the allowance the buyer approved for
+/// this canister. The memo is the token id, and a duplicate reported by the ledger counts as paid.
+pub async fn pay_seller(listing: &Listing, purchase: &Purchase) -> TransferOutcome {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: LedgerAccount {
+            owner: purchase.buyer,
+            subaccount: None,
+        },
+        to: ledger_account(&listing.seller),
+        amount: listing.price.into(),
+        fee: Some(purchase.fee.into()),
+        memo: Some(Memo::from(listing.token_id.to_be_bytes().to_vec())),
+        created_at_time: Some(purchase.created_at_time),
+    };
+
+    ledger::transfer_from(listing.ledger, args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing_is_locked_while_it_is_bought() {
+        let seller = Icrc1Account { owner: Principal::from_slice(&[1]), subaccount: None };
+        let listing = Listing { token_id: 7, seller, price: 500, ledger: Principal::from_slice(&[10]), listed_at: 0 };
+        let mut marketplace = MarketplaceStore::default();
+        assert!(marketplace.list(Listing { price: 0, ..listing.clone() }).is_err());
+        marketplace.list(listing).unwrap();
+
+        let buyer = Principal::from_slice(&[2]);
+        let (listed, purchase) = marketplace.begin_purchase(7, buyer, 10, 100).unwrap();
+        assert_eq!((listed.price, purchase.created_at_time), (500, 100));
+        assert!(marketplace.begin_purchase(7, buyer, 10, 200).is_err());
+        assert!(marketplace.delist(7).is_err());
+
+        // A payment that may have gone through stays with its buyer and is retried unchanged,
+        // even after a refusal.
+        marketplace.end_payment(7, &TransferOutcome::Unknown("call failed".to_string()));
+        assert!(marketplace.begin_purchase(7, Principal::from_slice(&[3]), 10, 200).is_err());
+        let (_, retry) = marketplace.begin_purchase(7, buyer, 20, 200).unwrap();
+        assert_eq!((retry.fee, retry.created_at_time), (10, 100));
+        marketplace.end_payment(7, &TransferOutcome::Rejected("bad fee".to_string()));
+        assert!(marketplace.delist(7).is_err());
+
+        // Past the ledger's window it waits to be resolved instead.
+        let later = 100 + ledger::LEDGER_TX_WINDOW + 1;
+        assert!(marketplace.begin_purchase(7, buyer, 10, later).is_err());
+        assert!(marketplace.resolve_purchase(7, &None, later).is_err());
+        let expired = later + 2 * ledger::LEDGER_PERMITTED_DRIFT;
+        assert_eq!(marketplace.resolve_purchase(7, &None, expired).unwrap().buyer, buyer);
+
+        // A refused payment leaves the token listed.
+        marketplace.begin_purchase(7, buyer, 10, expired).unwrap();
+        marketplace.end_payment(7, &TransferOutcome::Rejected("insufficient funds".to_string()));
+        assert!(marketplace.is_listed(7));
+        marketplace.begin_purchase(7, buyer, 10, expired).unwrap();
+        marketplace.clear(7);
+        assert!(!marketplace.is_listed(7));
+        assert!(marketplace.begin_purchase(7, buyer, 10, expired).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::reader::Reader;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell, StableLog, Storable};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use serde::de::DeserializeOwned;
+
+use super::account::Account;
+use super::distribution::DistributionRound;
+use super::dividends::HolderDividend;
+use super::escrow::Booking;
+use super::excess_refund::ExcessRefund;
+use super::settlement::Settlement;
+use super::{MetaDataState, TokenType};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Holds the heap part of `State`, written in `pre_upgrade`.
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const TOKENS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const OWNER_TO_TOKEN_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const BOOKED_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const METADATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
+const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(6);
+const DISTRIBUTION_ROUNDS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const HOLDER_DIVIDENDS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const BOOKINGS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const SETTLEMENTS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const EXCESS_REFUNDS_MEMORY_ID: MemoryId = MemoryId::new(11);
+
+/// Stable memory laid out by a `MemoryManager` starts with this magic.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    /// token id -> token
+    pub static TOKENS: RefCell<StableBTreeMap<u32, TokenType, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TOKENS_MEMORY_ID)));
+
+    /// (owner, token id) -> ()
+    pub static OWNER_TO_TOKEN_INDEX: RefCell<StableBTreeMap<(Account, u32), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(OWNER_TO_TOKEN_INDEX_MEMORY_ID)));
+
+    /// investor -> booked quantity
+    pub static BOOKED_TOKENS: RefCell<StableBTreeMap<Principal, u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOOKED_TOKENS_MEMORY_ID)));
+
+    /// (investor, booking id) -> booking
+    pub static BOOKINGS: RefCell<StableBTreeMap<(Principal, u64), Booking, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOOKINGS_MEMORY_ID)));
+
+    pub static SETTLEMENTS: RefCell<StableBTreeMap<(Principal, Principal), Settlement, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(SETTLEMENTS_MEMORY_ID)));
+
+    /// (investor, ledger) -> the last excess refunded after the sale
+    pub static EXCESS_REFUNDS: RefCell<StableBTreeMap<(Principal, Principal), ExcessRefund, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(EXCESS_REFUNDS_MEMORY_ID)));
+
+    pub static METADATA: RefCell<StableCell<Option<MetaDataState>, Memory>> = RefCell::new(
+        StableCell::init(memory(METADATA_MEMORY_ID), None).expect("Failed to initialize the metadata cell"),
+    );
+
+    /// The ICRC-3 block log.
+    pub static BLOCKS: RefCell<StableLog<Block, Memory, Memory>> = RefCell::new(
+        StableLog::init(memory(BLOCKS_INDEX_MEMORY_ID), memory(BLOCKS_DATA_MEMORY_ID))
+            .expect("Failed to initialize the block log"),
+    );
+
+    /// round id -> revenue distribution round
+    pub static DISTRIBUTION_ROUNDS: RefCell<StableBTreeMap<u64, DistributionRound, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DISTRIBUTION_ROUNDS_MEMORY_ID)));
+
+    /// holder -> dividends settled for it
+    pub static HOLDER_DIVIDENDS: RefCell<StableBTreeMap<Account, HolderDividend, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(HOLDER_DIVIDENDS_MEMORY_ID)));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// Whether stable memory still holds a whole `State` written by `stable_save`,
+/// i.e. the canister is being upgraded from before the memory manager.
+/// Must be called before anything touches the stable structures.
+pub fn has_legacy_state() -> bool {
+    let memory = DefaultMemoryImpl::default();
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    memory.read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+/// Writes the heap part of the state to its own memory, prefixed by its length.
+pub fn save_upgrade_state<T: CandidType>(state: &T) -> Result<(), String> {
+    let bytes = Encode!(state).map_err(|e| format!("Failed to encode state: {e}"))?;
+    let mut memory = memory(UPGRADES_MEMORY_ID);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write(&bytes))
+        .map_err(|e| format!("Failed to write state: {e:?}"))
+}
+
+pub fn load_upgrade_state<T: CandidType + DeserializeOwned>() -> Result<T, String> {
+    let memory = memory(UPGRADES_MEMORY_ID);
+    let mut reader = Reader::new(&memory, 0);
+
+    let mut len = [0; 8];
+    reader.read(&mut len).map_err(|e| format!("Failed to read state length: {e:?}"))?;
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    reader.read(&mut bytes).map_err(|e| format!("Failed to read state: {e:?}"))?;
+
+    Decode!(&bytes, T).map_err(|e| format!("Failed to decode state: {e}"))
+}
+
+/// An ICRC-3 block as stored in `BLOCKS`.
+pub struct Block(pub ICRC3Value);
+
+impl Storable for Block {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).expect("Failed to encode block"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Decode!(&bytes, ICRC3Value).expect("Failed to decode block"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TokenType {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode token"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode token")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Booking {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode booking"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode booking")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Settlement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode settlement"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode settlement")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ExcessRefund {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode excess refund"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode excess refund")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MetaDataState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode metadata"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("Failed to decode metadata")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -1,6 +1,9 @@
+use std::collections::BTreeSet;
+
 use candid::{CandidType, Deserialize, Nat, Principal};
 
 use super::models::GetMetadataRet;
+use super::pricing::PricingSchedule;
 
 
 
@@ -38,11 +41,142 @@ pub struct Metadata {
     pub symbol: String,
     pub treasury: Principal,
     pub images: Vec<String>,
+    /// Bookings open at this time, in nanoseconds since the epoch.
+    pub sale_start: Option<u64>,
+    /// Bookings close at this time and the sale moves to `SaleStatus::Closed`.
+    pub sale_end: Option<u64>,
+    /// What happens to a sale that closes at `sale_end`. Without one, the
+    /// collection owner accepts or rejects it.
+    pub sale_end_policy: Option<SaleEndPolicy>,
+    /// The least the sale has to raise before it can be accepted.
+    pub min_raise: Option<MinRaise>,
+    /// Tiered and early-bird prices; `price` applies where they do not.
+    pub pricing: Option<PricingSchedule>,
+    /// Ledgers the sale accepts besides `token`, each at its own price.
+    pub accepted_ledgers: Option<Vec<AcceptedLedger>>,
+}
+
+/// A ledger bookings can be paid in.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AcceptedLedger {
+    pub ledger: Principal,
+    /// The price of one token, in this ledger's units.
+    pub price: f64,
+    /// The ICRC index canister of `ledger`, used to find where refunds go when the investor
+    /// registered no refund account. Unused for ICP.
+    pub index: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum SaleEndPolicy {
+    Accept,
+    Reject,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum MinRaise {
+    /// A number of booked tokens.
+    Tokens(u128),
+    /// An amount in units of `token`. Bookings paid in other ledgers are converted at the ratio of their prices.
+    Amount(u128),
 }
 
 
 
 impl Metadata {
+    /// `token` at `price`, followed by the other accepted ledgers.
+    pub fn currencies(&self) -> Vec<AcceptedLedger> {
+        let token = AcceptedLedger {
+            ledger: self.token,
+            price: self.price,
+            index: self.index,
+        };
+        std::iter::once(token).chain(self.accepted_ledgers.iter().flatten().cloned()).collect()
+    }
+
+    /// The accepted ledger `ledger`, or `token` if none is given.
+    pub fn currency(&self, ledger: Option<Principal>) -> Result<AcceptedLedger, String> {
+        let ledger = ledger.unwrap_or(self.token);
+        self.currencies()
+            .into_iter()
+            .find(|currency| currency.ledger == ledger)
+            .ok_or(format!("Ledger {ledger} is not accepted by this sale."))
+    }
+
+    /// What booking `quantity` more tokens in `currency` costs at `now`, in its ledger units,
+    /// once `total_booked_tokens` are booked. Tiers and early-bird prices only apply to `token`.
+    pub fn booking_cost(&self, currency: &AcceptedLedger, total_booked_tokens: u128, quantity: u128, now: u64) -> u128 {
+        match &self.pricing {
+            Some(pricing) if currency.ledger == self.token => {
+                pricing.cost(self.price, total_booked_tokens, quantity, now)
+            }
+            _ => (quantity as f64 * currency.price) as u128,
+        }
+    }
+
+    /// `amount` in units of `ledger` converted to units of `token` at the ratio of their prices.
+    pub fn to_token_units(&self, ledger: Principal, amount: u128) -> u128 {
+        match self.currency(Some(ledger)) {
+            Ok(_) if ledger == self.token => amount,
+            Ok(currency) if currency.price > 0.0 => (amount as f64 * self.price / currency.price) as u128,
+            _ => 0,
+        }
+    }
+
+    /// `raised` is what the booked tokens cost, in ledger units.
+    pub fn min_raise_reached(&self, total_booked_tokens: u128, raised: u128) -> bool {
+        match self.min_raise {
+            None => true,
+            Some(MinRaise::Tokens(tokens)) => total_booked_tokens >= tokens,
+            Some(MinRaise::Amount(amount)) => raised >= amount,
+        }
+    }
+
+    /// Whether bookings are accepted at `now` as far as the sale window goes.
+    pub fn check_sale_window(&self, now: u64) -> Result<(), String> {
+        if self.sale_start.is_some_and(|start| now < start) {
+            return Err("Sale has not started.".to_string());
+        }
+        if self.sale_end.is_some_and(|end| now >= end) {
+            return Err("Sale has ended.".to_string());
+        }
+        Ok(())
+    }
+
+    /// Once tokens are booked, what they cost and where they were paid is fixed: the sale token,
+    /// its price and supply cap stay as they are, and no accepted ledger is dropped or repriced.
+    pub fn check_sale_terms_kept(&self, before: &Metadata) -> Result<(), String> {
+        if self.token != before.token || self.price != before.price || self.supply_cap != before.supply_cap {
+            return Err("The token, price and supply cap cannot change once tokens are booked.".to_string());
+        }
+        let currencies = self.currencies();
+        for currency in before.currencies() {
+            if !currencies.iter().any(|kept| kept.ledger == currency.ledger && kept.price == currency.price) {
+                return Err(format!(
+                    "Ledger {} cannot be removed or repriced once tokens are booked.",
+                    currency.ledger
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves an asset path, with or without a leading `/`, against the collection's asset canister.
+    /// Absolute URLs are returned unchanged.
+    pub fn asset_url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            let separator = if path.starts_with('/') { "" } else { "/" };
+            format!("https://{}.icp0.io{separator}{path}", self.asset_canister.to_text())
+        }
+    }
+
+    /// The image shown for each token: the first collection image, or the logo.
+    pub fn token_image_url(&self) -> String {
+        self.asset_url(self.images.first().unwrap_or(&self.logo))
+    }
+
     pub fn with_supply(&self, total_supply: Nat) -> GetMetadataRet {
         GetMetadataRet {
             weight: self.weight,
@@ -78,6 +212,12 @@ impl Metadata {
             symbol: self.symbol.clone(),
             treasury: self.treasury,
             images: self.images.clone(),
+            sale_start: self.sale_start,
+            sale_end: self.sale_end,
+            sale_end_policy: self.sale_end_policy.clone(),
+            min_raise: self.min_raise.clone(),
+            pricing: self.pricing.clone(),
+            accepted_ledgers: self.accepted_ledgers.clone(),
         }
     }
 
@@ -175,6 +315,40 @@ impl Metadata {
         if let Some(images) = args.images {
             self.images = images;
         }
+        if let Some(sale_start) = args.sale_start {
+            self.sale_start = Some(sale_start);
+        }
+        if let Some(sale_end) = args.sale_end {
+            self.sale_end = Some(sale_end);
+        }
+        if let Some(sale_end_policy) = args.sale_end_policy {
+            self.sale_end_policy = Some(sale_end_policy);
+        }
+        if let Some(min_raise) = args.min_raise {
+            self.min_raise = Some(min_raise);
+        }
+        if let Some(pricing) = args.pricing {
+            pricing.validate()?;
+            self.pricing = Some(pricing);
+        }
+
+        if let Some(accepted_ledgers) = args.accepted_ledgers {
+            self.accepted_ledgers = Some(accepted_ledgers);
+        }
+        let currencies = self.currencies();
+        if currencies.iter().any(|currency| currency.price.is_nan() || currency.price < 0.0) {
+            return Err("Prices must not be negative.".to_string());
+        }
+        let mut ledgers = BTreeSet::new();
+        if !currencies.iter().all(|currency| ledgers.insert(currency.ledger)) {
+            return Err("Each ledger can only be accepted once.".to_string());
+        }
+
+        if let (Some(start), Some(end)) = (self.sale_start, self.sale_end) {
+            if end <= start {
+                return Err("sale_end must be after sale_start.".to_string());
+            }
+        }
 
         // Return success with an updated supply cap
         Ok(self.supply_cap)
@@ -215,4 +389,91 @@ pub struct UpdateMetadataArgs {
     pub symbol: Option<String>,
     pub treasury: Option<Principal>,
     pub images: Option<Vec<String>>,
-}
\ No newline at end of file
+    pub sale_start: Option<u64>,
+    pub sale_end: Option<u64>,
+    pub sale_end_policy: Option<SaleEndPolicy>,
+    pub min_raise: Option<MinRaise>,
+    pub pricing: Option<PricingSchedule>,
+    pub accepted_ledgers: Option<Vec<AcceptedLedger>>,
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn metadata(token: Principal, price: f64) -> Metadata {
+        let principal = Principal::anonymous();
+        Metadata {
+            weight: 0.0,
+            drive_type: String::new(),
+            purchase_price: 0,
+            token,
+            documents: vec![],
+            supply_cap: 100,
+            displays: String::new(),
+            seating: String::new(),
+            cargo: 0.0,
+            logo: String::new(),
+            name: String::new(),
+            overall_height: 0.0,
+            description: String::new(),
+            overall_width: 0.0,
+            track_front: 0.0,
+            collection_owner: principal,
+            asset_canister: principal,
+            ground_clearance: 0.0,
+            key_features: vec![],
+            range_per_charge: 0.0,
+            track_rear: 0.0,
+            acceleration: String::new(),
+            charging_speed: String::new(),
+            wheels: 0.0,
+            brochure_url: String::new(),
+            index: principal,
+            price,
+            battery: String::new(),
+            overall_length: 0.0,
+            symbol: String::new(),
+            treasury: principal,
+            images: vec![],
+            sale_start: None,
+            sale_end: None,
+            sale_end_policy: None,
+            min_raise: None,
+            pricing: None,
+            accepted_ledgers: None,
+        }
+    }
+
+    #[test]
+    fn test_asset_urls_resolve_against_the_asset_canister() {
+        let mut metadata = metadata(Principal::anonymous(), 1.0);
+        metadata.asset_canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+        let expected = "https://ryjl3-tyaaa-aaaaa-aaaba-cai.icp0.io/images/1.png";
+        assert_eq!(metadata.asset_url("images/1.png"), expected);
+        assert_eq!(metadata.asset_url("/images/1.png"), expected);
+        assert_eq!(metadata.asset_url("https://example.com/1.png"), "https://example.com/1.png");
+        assert_eq!(metadata.asset_url("http://example.com/1.png"), "http://example.com/1.png");
+    }
+
+    #[test]
+    fn test_sale_terms_are_kept_once_tokens_are_booked() {
+        let token = Principal::from_slice(&[10]);
+        let ckusdc = AcceptedLedger { ledger: Principal::from_slice(&[11]), price: 50.0, index: token };
+        let mut before = metadata(token, 100.0);
+        before.accepted_ledgers = Some(vec![ckusdc.clone()]);
+
+        let mut after = before.clone();
+        after.name = "renamed".to_string();
+        assert!(after.check_sale_terms_kept(&before).is_ok());
+        after.supply_cap += 1;
+        assert!(after.check_sale_terms_kept(&before).is_err());
+
+        let mut after = before.clone();
+        after.accepted_ledgers = Some(vec![AcceptedLedger { price: 40.0, ..ckusdc }]);
+        assert!(after.check_sale_terms_kept(&before).is_err());
+        after.accepted_ledgers = None;
+        assert!(after.check_sale_terms_kept(&before).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, HashMap};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::storage;
+
+use super::escrow::{EscrowStore, SaleStatus};
+use super::models::Icrc7TokenMetadataRetItemInnerItem1;
+use super::{MetaDataState, Owner, State, TokenMetadata, TokenState, TokenType, SERIAL_NUMBER_KEY};
+
+/// `State` as it was written by `stable_save`, before tokens, bookings and
+/// metadata moved to stable structures. Fields that were added later are
+/// left out so that candid skips them.
+#[derive(CandidType, Deserialize)]
+struct LegacyState {
+    metadata: Option<MetaDataState>,
+    escrow: LegacyEscrowStore,
+    tokens: LegacyTokenState,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyEscrowStore {
+    sale_status: SaleStatus,
+    booked_tokens: HashMap<Principal, u128>,
+    total_booked_tokens: u128,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyTokenState {
+    counter: u32,
+    tokens: BTreeMap<u32, LegacyToken>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyToken {
+    owner: Owner,
+}
+
+/// Reads the state saved by `stable_save` and moves it into the stable structures.
+/// The block log of the old layout is not carried over.
+pub fn restore_legacy_state() -> Result<State, String> {
+    let (legacy,): (LegacyState,) =
+        storage::stable_restore().map_err(|e| format!("Failed to decode legacy state: {e}"))?;
+
+    let mut state = State {
+        escrow: EscrowStore {
+            sale_status: legacy.escrow.sale_status,
+            ..Default::default()
+        },
+        tokens: TokenState {
+            counter: legacy.tokens.counter,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    if let Some(metadata) = legacy.metadata {
+        state.set_metadata(metadata);
+    }
+    for (investor, quantity) in legacy.escrow.booked_tokens {
+        state.escrow.book_tokens(investor, quantity);
+    }
+    state.escrow.total_booked_tokens = legacy.escrow.total_booked_tokens;
+
+    for (token_id, token) in legacy.tokens.tokens {
+        let mut metadata = TokenMetadata::new();
+        metadata.insert(
+            SERIAL_NUMBER_KEY.to_string(),
+            Icrc7TokenMetadataRetItemInnerItem1::Nat(token_id.into()),
+        );
+        state.tokens.insert(token_id, TokenType { owner: token.owner, metadata });
+    }
+
+    Ok(state)
+}
+
@@ -7,8 +7,24 @@ pub mod subaccount;
 pub mod metadata;
 pub mod transactions;
 pub mod icrc7;
+pub mod icrc3;
 pub mod index_canister;
 pub mod  token;
+pub mod approvals;
+pub mod settings;
+pub mod deduplication;
+pub mod account;
+pub mod memory;
+pub mod migration;
+pub mod distribution;
+pub mod dividends;
+pub mod sale;
+pub mod compliance;
+pub mod pricing;
+pub mod ledger;
+pub mod settlement;
+pub mod excess_refund;
+pub mod marketplace;
 pub use  token::*;
 pub mod icrc1;
 
@@ -5,7 +5,9 @@ use candid::{self, CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_cdk::api::call::CallResult as CallResult;
 use serde::Serialize;
 
-use super::metadata::Metadata;
+use super::escrow::BookingPayment;
+use super::metadata::{AcceptedLedger, Metadata, MinRaise, SaleEndPolicy};
+use super::pricing::PricingSchedule;
 
 
 
@@ -31,7 +33,13 @@ pub enum AcceptSaleRet { Ok(bool), Err(String) }
 pub enum AcceptSaleIndividualRet { Ok(bool), Err(String) }
 
 #[derive(CandidType, Deserialize, Clone)]
-pub struct BookTokensArg { pub quantity: u32 }
+pub struct BookTokensArg {
+  pub quantity: u32,
+  /// `BookingPayment::Escrow` if not set.
+  pub payment: Option<BookingPayment>,
+  /// The ledger the booking is paid in; `Metadata.token` if not set.
+  pub ledger: Option<Principal>,
+}
 
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -84,6 +92,12 @@ pub struct GetMetadataRet {
   pub symbol: String,
   pub treasury: Principal,
   pub images: Vec<String>,
+  pub sale_start: Option<u64>,
+  pub sale_end: Option<u64>,
+  pub sale_end_policy: Option<SaleEndPolicy>,
+  pub min_raise: Option<MinRaise>,
+  pub pricing: Option<PricingSchedule>,
+  pub accepted_ledgers: Option<Vec<AcceptedLedger>>,
 }
 
 
@@ -128,7 +142,7 @@ pub struct Icrc7OwnerOfRetItemInner {
   pub subaccount: Option<Vec<u8>>,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub enum Icrc7TokenMetadataRetItemInnerItem1MapItem1 {
   Int(candid::Int),
   Nat(candid::Nat),
@@ -136,7 +150,7 @@ pub enum Icrc7TokenMetadataRetItemInnerItem1MapItem1 {
   Text(String),
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub enum Icrc7TokenMetadataRetItemInnerItem1ArrayItem {
   Int(candid::Int),
   Nat(candid::Nat),
@@ -144,7 +158,7 @@ pub enum Icrc7TokenMetadataRetItemInnerItem1ArrayItem {
   Text(String),
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub enum Icrc7TokenMetadataRetItemInnerItem1 {
   Int(candid::Int),
   Map(Vec<(String,Icrc7TokenMetadataRetItemInnerItem1MapItem1,)>),
@@ -187,6 +201,31 @@ pub enum Icrc7TransferRetItemInnerErr {
   TooOld,
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Icrc7BurnArgItem {
+  pub token_id: u32,
+  pub memo: Option<Vec<u8>>,
+  pub from_subaccount: Option<Vec<u8>>,
+  pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Icrc7BurnRetItemInnerErr {
+  GenericError{ message: String, error_code: candid::Nat },
+  Duplicate{ duplicate_of: candid::Nat },
+  NonExistingTokenId,
+  Unauthorized,
+  CreatedInFuture{ ledger_time: u64 },
+  GenericBatchError{ message: String, error_code: candid::Nat },
+  TooOld,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Icrc7BurnRetItemInner {
+  Ok(candid::Nat),
+  Err(Icrc7BurnRetItemInnerErr),
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub  struct TransferArgs {
  pub to: Icrc1Account,
@@ -211,7 +250,7 @@ pub enum TransferError {
 
 #[derive(CandidType, Deserialize, Clone)]
 pub enum Icrc7TransferRetItemInner {
-  Ok(u32),
+  Ok(candid::Nat),
   Err(Icrc7TransferRetItemInnerErr),
 }
 
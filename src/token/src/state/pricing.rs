@@ -0,0 +1,92 @@
+use candid::{CandidType, Deserialize};
+
+/// Token prices that replace the flat `Metadata.price`, which still applies once the tiers run out.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PricingSchedule {
+    /// Consecutive tiers in ascending order of `up_to`.
+    pub tiers: Vec<PriceTier>,
+    /// Bookings made inside a window pay its price for every token, ahead of the tiers.
+    pub early_bird: Vec<EarlyBirdWindow>,
+}
+
+/// The price of each token booked while the sale's total stays below `up_to`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceTier {
+    pub up_to: u128,
+    pub price: f64,
+}
+
+/// The price of every token booked before `until`, in nanoseconds since the epoch.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EarlyBirdWindow {
+    pub until: u64,
+    pub price: f64,
+}
+
+impl PricingSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tiers.windows(2).any(|pair| pair[0].up_to >= pair[1].up_to) {
+            return Err("Price tiers must be in ascending order of up_to.".to_string());
+        }
+        let prices = self.tiers.iter().map(|tier| tier.price);
+        if prices.chain(self.early_bird.iter().map(|window| window.price)).any(|price| price.is_nan() || price < 0.0) {
+            return Err("Prices must not be negative.".to_string());
+        }
+        Ok(())
+    }
+
+    /// What booking `quantity` tokens costs at `now` once `booked` tokens are booked in the
+    /// sale, in ledger units. Tokens past the last tier cost `base_price`.
+    pub fn cost(&self, base_price: f64, booked: u128, quantity: u128, now: u64) -> u128 {
+        let early_bird = self
+            .early_bird
+            .iter()
+            .filter(|window| now < window.until)
+            .min_by_key(|window| window.until);
+        if let Some(window) = early_bird {
+            return (quantity as f64 * window.price) as u128;
+        }
+
+        let mut cost = 0;
+        let mut position = booked;
+        let mut remaining = quantity;
+        for tier in &self.tiers {
+            if remaining == 0 {
+                break;
+            }
+            if position < tier.up_to {
+                let count = remaining.min(tier.up_to - position);
+                cost += (count as f64 * tier.price) as u128;
+                position += count;
+                remaining -= count;
+            }
+        }
+        cost + (remaining as f64 * base_price) as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bookings_span_tiers() {
+        let schedule = PricingSchedule {
+            tiers: vec![PriceTier { up_to: 10, price: 100.0 }, PriceTier { up_to: 20, price: 150.0 }],
+            early_bird: vec![EarlyBirdWindow { until: 1_000, price: 80.0 }],
+        };
+        assert!(schedule.validate().is_ok());
+
+        assert_eq!(schedule.cost(200.0, 0, 5, 999), 400);
+        assert_eq!(schedule.cost(200.0, 0, 5, 1_000), 500);
+        // Two tokens left in the first tier, ten in the second, three at the base price.
+        assert_eq!(schedule.cost(200.0, 8, 15, 1_000), 200 + 1_500 + 600);
+        assert_eq!(schedule.cost(200.0, 25, 1, 1_000), 200);
+
+        let unordered = PricingSchedule {
+            tiers: vec![PriceTier { up_to: 20, price: 1.0 }, PriceTier { up_to: 10, price: 1.0 }],
+            early_bird: vec![],
+        };
+        assert!(unordered.validate().is_err());
+    }
+}
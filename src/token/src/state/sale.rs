@@ -0,0 +1,62 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use ic_cdk_timers::TimerId;
+
+use crate::STATE;
+
+use super::escrow::SaleStatus;
+use super::metadata::SaleEndPolicy;
+
+/// How long `close_sale` waits before it takes up a settlement that failed part way again.
+const SETTLEMENT_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    static SALE_END_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+/// Schedules `close_sale` at `sale_end`, replacing any earlier schedule.
+/// Timers do not survive upgrades, so this runs after install, upgrade and every metadata update.
+pub fn schedule_sale_end() {
+    if let Some(timer) = SALE_END_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+
+    let Some(sale_end) = STATE.with_borrow(|f| f.metadata()).and_then(|f| f.metadata.sale_end) else {
+        return;
+    };
+    let delay = Duration::from_nanos(sale_end.saturating_sub(ic_cdk::api::time()));
+    let timer = ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(close_sale()));
+    SALE_END_TIMER.set(Some(timer));
+}
+
+/// Stops bookings of a live sale and finalizes it according to `sale_end_policy`.
+/// An `Accept` policy rejects the sale if it did not reach `min_raise`. A settlement that
+/// fails part way is taken up again after `SETTLEMENT_RETRY_DELAY`.
+async fn close_sale() {
+    SALE_END_TIMER.set(None);
+
+    let state = STATE.with_borrow_mut(|f| {
+        if f.escrow.sale_status == SaleStatus::Live {
+            f.escrow.update_sale_status(SaleStatus::Closed);
+        }
+        f.clone()
+    });
+    let Some(metadata) = state.metadata().map(|f| f.metadata) else {
+        return;
+    };
+
+    let result = match (&state.escrow.sale_status, &metadata.sale_end_policy) {
+        // A sale that missed its minimum raise cannot be accepted, so it is refunded instead.
+        (SaleStatus::Closed, Some(SaleEndPolicy::Accept)) if state.escrow.summary(&metadata).min_raise_reached => {
+            state.accept_sale().await
+        }
+        (SaleStatus::Closed, Some(_)) => state.reject_sale().await,
+        (SaleStatus::Settling, Some(SaleEndPolicy::Accept)) => state.accept_sale().await,
+        _ => return,
+    };
+    if result.is_err() && STATE.with_borrow(|f| f.escrow.sale_status == SaleStatus::Settling) {
+        let timer = ic_cdk_timers::set_timer(SETTLEMENT_RETRY_DELAY, || ic_cdk::spawn(close_sale()));
+        SALE_END_TIMER.set(Some(timer));
+    }
+}
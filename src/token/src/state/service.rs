@@ -1,253 +1,347 @@
 #![allow(dead_code, unused_imports)]
 
-use crate::{state::{icrc1, Owner}, validations, STATE};
+use crate::{state::{account::Account as AccountKey, approvals::*, deduplication::{check_window, transaction_hash}, distribution::{self, DistributionRound, QueuedPayout}, dividends, excess_refund::{self, ExcessRefund}, marketplace::{self, Listing, Purchase}, icrc1, ledger::{self, TransferOutcome}, settlement::{self, Settlement, SettlementStatus}, token::*, transactions::Transaction, Owner}, validations, STATE};
 
 use super::{
-    escrow::{EscrowStore, SaleStatus}, metadata::Metadata, models::*, subaccount::{AccountIdentifier, Subaccount}, State, TokenState
+    escrow::{self, Booking, BookingKind, BookingPayment, PendingRefund, EscrowReconciliation, EscrowStore, ReconciliationRow, ReconciliationTotal, RefundResult, SaleStatus, SaleSummary}, metadata::{AcceptedLedger, Metadata}, models::*, subaccount::{AccountIdentifier, Subaccount}, MetaDataState, State, TokenState
 };
 use candid::{self, CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_cdk::{api::call::CallResult, caller};
 use ic_ledger_types::{Memo,  Tokens, DEFAULT_SUBACCOUNT};
+use std::collections::BTreeSet;
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 impl State {
-    pub async fn accept_sale(&self) -> Result<bool, String> {
-        // Check if the sale is live
-        let escrow_store = self.escrow.clone();
-        if escrow_store.sale_status != SaleStatus::Live {
-            return Err("Sale not live.".to_string());
-        }
+    /// Appends `transaction` to the block log and re-certifies its tip.
+    fn record(&mut self, transaction: Transaction) -> Nat {
+        let block_index = self.transactions.record(transaction, ic_cdk::api::time());
+        self.transactions.certify();
+        block_index
+    }
 
+    /// Mints a new token with its mint-time metadata and records a `7mint` block for it.
+    /// `price` is what the holder paid for the token, in ledger units.
+    pub fn mint_token(&mut self, principal: Principal, subaccount: Option<Vec<u8>>, price: u64, ledger: Principal) -> u32 {
+        let mut metadata = TokenMetadata::new();
+        metadata.insert(
+            MINTED_AT_KEY.to_string(),
+            Icrc7TokenMetadataRetItemInnerItem1::Nat(ic_cdk::api::time().into()),
+        );
+        metadata.insert(
+            PURCHASE_PRICE_KEY.to_string(),
+            Icrc7TokenMetadataRetItemInnerItem1::Nat(price.into()),
+        );
+        metadata.insert(
+            PURCHASE_LEDGER_KEY.to_string(),
+            Icrc7TokenMetadataRetItemInnerItem1::Text(ledger.to_text()),
+        );
 
-        // Retrieve treasury, ledger, and booked tokens
-        let metadata = self.metadata.clone().map(|f| f.metadata);
-        if metadata.is_none() {
-            return Err("Metadata not set".into());
+        if let Some(state) = self.metadata() {
+            self.update_metadata_state(MetaDataState::increment_supply);
+
+            let collection = &state.metadata;
+            metadata.insert(
+                SHARE_KEY.to_string(),
+                Icrc7TokenMetadataRetItemInnerItem1::Map(vec![
+                    ("numerator".to_string(), Icrc7TokenMetadataRetItemInnerItem1MapItem1::Nat(1u8.into())),
+                    ("denominator".to_string(), Icrc7TokenMetadataRetItemInnerItem1MapItem1::Nat(collection.supply_cap.into())),
+                ]),
+            );
+            metadata.insert(
+                IMAGE_KEY.to_string(),
+                Icrc7TokenMetadataRetItemInnerItem1::Text(collection.token_image_url()),
+            );
         }
-        let metadata = metadata.unwrap();
-        let treasury = metadata.treasury;
-        let ledger = metadata.token;
-        let booked_tokens = escrow_store.get_booked_tokens();
-
-        for (investor, quantity) in booked_tokens.iter().filter(|f| f.1 > &0) {
-            let price = metadata.price;
-            let user_invested_amount = quantity.clone() as u64 * (price) as u64;
-            // Transfer funds to treasury
-            const TRANSFER_FEE: u64 = 10_000;
 
-            let args  =TransferArg {
-                from_subaccount: Some(Subaccount::from(&investor.clone()).0),
-                to: Account {
-                    owner: treasury,
-                    subaccount: None,
-                },
-                fee: Some(TRANSFER_FEE.into()),
-                created_at_time: None,
-                memo: None,
-                amount: user_invested_amount.into(),
-            };
+        let token_id = self.tokens.mint(principal, subaccount.clone(), metadata);
+        self.record(Transaction::Mint {
+            token_id,
+            to: Owner { principal, subaccount },
+            memo: None,
+        });
+        token_id
+    }
 
-            let transaction = icrc1::icrc1_transfer(ledger, args.clone()).await.map_err(|f| format!("Failed to do icrc1 transfer: {quantity} * {price} = {user_invested_amount} {f:?} {args:?}"))?;
-
-            // let args = TransferArgs {
-            //     to: Icrc1Account {
-            //         owner: treasury,
-            //         subaccount: None,
-            //     },
-            //     from_subaccount: Some(Subaccount::from(&investor.clone()).to_vec()),
-            //     fee: Some(TRANSFER_FEE.clone().into()),
-            //     memo: None,
-            //     created_at_time: None,
-            //     amount: user_invested_amount as u64,
-            // };
-
-            // // let encoded_args = &args/*  Encode!(&args).expect("Failed to encode arguments") */;
-            
-            // // let args = ic_ledger_types::TransferArgs {
-            // //   memo: Memo(0),
-            // //   amount: Tokens::from_e8s(user_invested_amount as u64),
-            // //   fee: Tokens::from_e8s(TRANSFER_FEE),
-            // //   from_subaccount: Some(ic_ledger_types::Subaccount::from(investor.clone())),
-            // //   to: ic_ledger_types::AccountIdentifier::from_hex(&AccountIdentifier::from_principal(treasury, None).to_hex())?,
-            // //   created_at_time: None,
-            // // };
-            // let encoded_args = args.clone()/*  Encode!(&args).expect("Failed to encode arguments") */;
-
-            // let (_transfer_result,): (Result<Nat, ic_ledger_types::TransferError> , ) = ic_cdk::call(
-            //     ledger,
-            //     "icrc1_transfer",
-            //     (encoded_args,),
-            // )
-            // .await
-            // .map_err(|err| format!("Icrc1 Transfer call failed: {err:?} for owner: {}, invester: {}, args: {args:?}", treasury.to_text(), investor.to_text(), ))?;
-
-            // _transfer_result.map_err(|e| format!("Icrc1 Transfer failed {e:?}") )?;
-
-            
-            
-
-            // Mint tokens for the investor
-            for _ in 0..quantity.clone() {
-                STATE.with_borrow_mut(|f|{
-                    f.tokens.mint(
-                        *investor,
-                        Some(Subaccount::from(investor).to_vec()),
-                    );
-                    f.metadata.as_mut().map(|f| f.increment_supply());
-                } );
-                
-            }
+    pub fn extend_token_metadata(
+        &mut self,
+        token_ids: Vec<u32>,
+        entries: Vec<(String, Icrc7TokenMetadataRetItemInnerItem1)>,
+    ) -> Result<bool, String> {
+        if let Some(token_id) = token_ids.iter().find(|id| !self.tokens.contains(**id)) {
+            return Err(format!("Token {token_id} does not exist."));
         }
 
-         // Accept the sale
-        STATE.with_borrow_mut(|f| f.escrow.accept_sale());
-
+        for token_id in token_ids {
+            self.tokens.extend_metadata(token_id, &entries)?;
+        }
         Ok(true)
     }
-    pub async fn accept_sale_individual_icrc1_transfer(invester: Principal, quantity: u128, metadata: Option<Metadata>, sale_status: SaleStatus ) -> Result<bool, String> {
-        // Check if the sale is live
-        if sale_status != SaleStatus::Live {
-            return Err("Sale not live.".to_string());
-        }
-        // Retrieve treasury, ledger, and booked tokens
-        if metadata.is_none() {
-            return Err("Metadata not set".into());
-        }
-        let metadata = metadata.unwrap();
-
-            let price = metadata.price;
-            let ledger =  metadata.token;
-            let treasury  = metadata.treasury;
-            let user_invested_amount = quantity.clone() as u64 * (price) as u64;
-            // Transfer funds to treasury
-            const TRANSFER_FEE: u64 = 10_000;
-
-            let args  =TransferArg {
-                from_subaccount: Some(Subaccount::from(&invester.clone()).0),
-                to: Account {
-                    owner: treasury,
-                    subaccount: None,
-                },
-                fee: Some(TRANSFER_FEE.into()),
-                created_at_time: None,
-                memo: None,
-                amount: user_invested_amount.into(),
-            };
 
-            let _ = icrc1::icrc1_transfer(ledger, args.clone()).await.map_err(|f| format!("Failed to do icrc1 transfer: {quantity} * {price} = {user_invested_amount} {f:?} {args:?}"))?;
+    /// Burns a token and records a `7burn` block for it.
+    /// Returns the block index, or `None` if the token does not exist.
+    pub fn burn_token(&mut self, token_id: u32, memo: Option<Vec<u8>>) -> Option<Nat> {
+        let from = self.tokens.burn(token_id)?;
+        self.update_metadata_state(MetaDataState::decrement_supply);
+        self.approvals.clear_token(token_id);
+        self.marketplace.clear(token_id);
 
-        Ok(true)
+        Some(self.record(Transaction::Burn { token_id, from, memo }))
     }
-    
 
-    pub async fn get_excess_escrow_balance(&self) -> Result<Vec<Principal>, String> {
-
-        let metadata = self.get_metadata().await?; // Assume this retrieves the Metadata struct
+    /// Moves a token to `to`, drops its token approvals and listing and records the transfer.
+    /// `spender` is set when an ICRC-37 spender moves the token for its owner.
+    fn transfer_token(
+        &mut self,
+        token_id: u32,
+        from: Owner,
+        to: Owner,
+        spender: Option<Owner>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> Nat {
+        self.tokens
+            .transfer(token_id, to.principal, to.subaccount.clone());
+        self.approvals.clear_token(token_id);
+        self.marketplace.clear(token_id);
+
+        self.record(Transaction::Transfer {
+            token_id,
+            from,
+            to,
+            spender,
+            memo,
+            created_at_time,
+        })
+    }
 
-        let escrow_store = self.escrow.clone(); // Assume this retrieves the EscrowStore instance
+    /// Charges every investor in each ledger they paid in and mints their tokens. Each
+    /// investor's progress is kept, so a call that fails part way can be repeated until
+    /// everyone is settled.
+    pub async fn accept_sale(&self) -> Result<bool, String> {
+        let metadata = self.metadata().map(|f| f.metadata).ok_or("Metadata not set".to_string())?;
+        STATE.with_borrow_mut(|f| f.escrow.begin_settlement(&metadata, ic_cdk::api::time()))?;
+
+        let mut errors = Vec::new();
+        for settlement in settlement::unsettled() {
+            let key = settlement.key();
+            let (investor, ledger) = key;
+            if settlement.status == SettlementStatus::Pending {
+                if let Err(e) = Self::pay_settlement(&key, metadata.treasury).await {
+                    errors.push(format!("{investor} in {ledger}: {e}"));
+                }
+            }
 
-        if escrow_store.sale_status == SaleStatus::Live {
-            return Err("Sale is live.".to_string());
+            // Minting happens in one message with the status change, so it cannot repeat.
+            STATE.with_borrow_mut(|f| {
+                let Some(settlement) = settlement::get(&key) else {
+                    return;
+                };
+                let SettlementStatus::Transferred { block_index } = settlement.status else {
+                    return;
+                };
+                // What each token cost on average in this ledger, given the tiers the bookings fell into.
+                let price = (settlement.amount / settlement.quantity) as u64;
+                for _ in 0..settlement.quantity {
+                    f.mint_token(investor, Some(Subaccount::from(&investor).to_vec()), price, ledger);
+                }
+                settlement::insert(Settlement {
+                    status: SettlementStatus::Minted { block_index },
+                    ..settlement
+                });
+            });
         }
 
-        let mut excess = Vec::new();
-
-        for principal in escrow_store.get_participating_investors() {
-
-            let subaccount = Subaccount::from(&principal);
-        let icp_ledger = metadata.token;
+        if !errors.is_empty() {
+            return Err(format!(
+                "{} settlements are not done yet; call accept_sale again to retry: {}",
+                errors.len(),
+                errors.join("; ")
+            ));
+        }
+        STATE.with_borrow_mut(|f| f.escrow.accept_sale());
+        excess_refund::schedule_refunds();
+        Ok(true)
+    }
 
-        let escrow_balance = EscrowStore::icrc1_balance_of(
-            icp_ledger,
-            Icrc1Account {
-                owner: ic_cdk::id(),
-                subaccount: Some(subaccount.to_vec()),
+    /// Sends one investor's payment to the treasury, unless it is already made or waits to be resolved.
+    async fn pay_settlement(key: &(Principal, Principal), treasury: Principal) -> Result<(), String> {
+        let outcome = match ledger::ledger_info(key.1).await {
+            Ok(info) => match settlement::prepare_payment(key, info.fee, ic_cdk::api::time())? {
+                Some(settlement) => settlement::send_payment(treasury, &settlement).await,
+                None => return Ok(()),
             },
-        )
-        .await?;
-
-        let total_invested_count = escrow_store
-            .get_booked_tokens()
-            .get(&principal)
-            .cloned()
-            .unwrap_or_else(|| 0);
-
-        let total_cost = (total_invested_count ) as f64
-            * &(metadata.price);
-
-        if (escrow_balance as f64) > total_cost {
-            excess.push(principal);
+            Err(e) => TransferOutcome::Rejected(e),
+        };
+        settlement::record_payment(key, &outcome);
+        match outcome {
+            TransferOutcome::Paid(_) => Ok(()),
+            TransferOutcome::Rejected(e) | TransferOutcome::Unknown(e) => Err(e),
         }
+    }
 
+    pub async fn get_excess_escrow_balance(&self) -> Result<Vec<Principal>, String> {
+        if self.escrow.is_open() {
+            return Err("Sale is live.".to_string());
         }
 
+        let mut excess: Vec<Principal> = Vec::new();
+        for row in self.reconcile_escrow().await?.rows {
+            if row.excess > 0 && !excess.contains(&row.investor) {
+                excess.push(row.investor);
+            }
+        }
         Ok(excess)
+    }
 
+    /// Reads every investor's escrow balance in every accepted ledger and sets it against
+    /// what they still owe there. Payments already sent by `accept_sale` are no longer owed.
+    pub async fn reconcile_escrow(&self) -> Result<EscrowReconciliation, String> {
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
+
+        let mut rows = Vec::new();
+        let mut totals = Vec::new();
+        for currency in metadata.currencies() {
+            let fee = ledger::ledger_info(currency.ledger).await?.fee;
+            let mut total = ReconciliationTotal::new(currency.ledger);
+
+            for investor in self.escrow.get_participating_investors() {
+                let escrow_balance = EscrowStore::icrc1_balance_of(
+                    currency.ledger,
+                    Icrc1Account {
+                        owner: ic_cdk::id(),
+                        subaccount: Some(Subaccount::from(&investor).to_vec()),
+                    },
+                )
+                .await?;
+
+                let paid = settlement::get(&(investor, currency.ledger))
+                    .is_some_and(|settlement| settlement.status != SettlementStatus::Pending);
+                let position = if paid {
+                    Default::default()
+                } else {
+                    self.escrow.position(&investor, currency.ledger, &metadata)
+                };
+
+                let row = ReconciliationRow::new(investor, currency.ledger, position, escrow_balance, fee);
+                total.add(&row);
+                rows.push(row);
+            }
+            totals.push(total);
+        }
 
+        Ok(EscrowReconciliation {
+            sale_status: STATE.with_borrow(|f| f.escrow.sale_status.clone()),
+            rows,
+            totals,
+        })
     }
 
     /// Should not be anonymous
     pub async fn book_tokens(&self, arg: BookTokensArg) -> Result<bool, String> {
         let principal = caller();
-        let metadata = self.get_metadata().await?; // Assume this retrieves the Metadata struct
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
 
-        let mut escrow_store = self.escrow.clone(); // Assume this retrieves the EscrowStore instance
+        let escrow_store = self.escrow.clone(); // Assume this retrieves the EscrowStore instance
 
         if escrow_store.sale_status != SaleStatus::Live {
             return Err("Sale not live.".to_string());
         }
+        metadata.check_sale_window(ic_cdk::api::time())?;
 
         if arg.quantity <= 0 {
             return Err("Quantity should be at least 1.".to_string());
         }
-
-        
+        self.compliance
+            .check_booking(&principal, escrow_store.booked_tokens_of(&principal), arg.quantity as u128)?;
 
         let subaccount = Subaccount::from(&principal);
-        let icp_ledger = metadata.token;
-
-        let escrow_balance = EscrowStore::icrc1_balance_of(
-            icp_ledger,
-            Icrc1Account {
-                owner: ic_cdk::id(),
-                subaccount: Some(subaccount.to_vec()),
-            },
-        )
-        .await?;
-
-        let total_invested_count = escrow_store
-            .get_booked_tokens()
-            .get(&principal)
-            .cloned()
-            .unwrap_or_else(|| 0);
+        let currency = metadata.currency(arg.ledger)?;
+        let icp_ledger = currency.ledger;
+        let fee = ledger::ledger_info(icp_ledger).await?.fee;
+
+        let Some(BookingPayment::TransferFrom { from_subaccount }) = arg.payment else {
+            let escrow_balance = EscrowStore::icrc1_balance_of(
+                icp_ledger,
+                Icrc1Account {
+                    owner: ic_cdk::id(),
+                    subaccount: Some(subaccount.to_vec()),
+                },
+            )
+            .await?;
+
+            // Other bookings may have landed while the balance was read, so the limits,
+            // the supply cap and the price are all settled against the current state.
+            STATE.with_borrow_mut(|f| {
+                f.record_booking(principal, &currency, arg.quantity as u128, Some(escrow_balance), fee, ic_cdk::api::time())
+            })?;
+            return Ok(true);
+        };
 
-        let total_cost = ((&total_invested_count + &(arg.quantity as u128)) as f64)
-            * &(metadata.price + 10_000.0);
+        let from = Account {
+            owner: principal,
+            subaccount: from_subaccount
+                .map(|subaccount| subaccount.as_slice().try_into())
+                .transpose()
+                .map_err(|_| "Subaccounts must be 32 bytes long.".to_string())?,
+        };
+        // The booking holds its tokens and price while the payment is pulled, and is taken back if that fails.
+        // Until then the investor's bookings can be neither cancelled nor settled.
+        let booking = STATE.with_borrow_mut(|f| {
+            f.escrow.begin_pull(principal)?;
+            let booking = f.record_booking(principal, &currency, arg.quantity as u128, None, fee, ic_cdk::api::time());
+            if booking.is_err() {
+                f.escrow.end_pull(&principal);
+            }
+            booking
+        })?;
+        // As with deposits, a ledger fee per booked token is kept in escrow on top of the price.
+        let amount = booking.amount + booking.quantity * fee;
+        let result = EscrowStore::pull_payment(icp_ledger, from, subaccount, amount, fee, &booking).await;
+        STATE.with_borrow_mut(|f| {
+            match &result {
+                Ok(block_index) => f.escrow.set_payment_block_index(principal, booking.id, block_index.clone()),
+                Err(_) => f.escrow.revert_booking(principal, &booking),
+            }
+            f.escrow.end_pull(&principal);
+        });
+        result.map(|_| true)
+    }
 
-        if (escrow_balance as f64) < total_cost {
-            return Err(format!("Invalid balance in escrow. Req quantity: {} Total invested: {total_invested_count} Current balanace: {escrow_balance}, total cost in e8s: {total_cost}", arg.quantity));
+    /// Books `quantity` tokens for `investor` at the price they cost now, provided
+    /// `escrow_balance` covers everything the investor owes, with a ledger `fee` per booked token.
+    /// Bookings paid after they are made pass no balance. The sale may have been closed or
+    /// rejected while the caller waited for the ledger, so it is checked again here.
+    fn record_booking(&mut self, investor: Principal, currency: &AcceptedLedger, quantity: u128, escrow_balance: Option<u128>, fee: u128, now: u64) -> Result<Booking, String> {
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
+        if self.escrow.sale_status != SaleStatus::Live {
+            return Err("Sale not live.".to_string());
         }
+        metadata.check_sale_window(now)?;
+        let booked = self.escrow.booked_tokens_of(&investor);
+        self.compliance.check_booking(&investor, booked, quantity)?;
 
-        ic_cdk::println!("Escrow balance {escrow_balance}, cost {total_cost} ");
-
-        if &escrow_store.total_booked_tokens + &(arg.quantity as u128) > metadata.supply_cap {
+        if self.escrow.total_booked_tokens + quantity > metadata.supply_cap {
             return Err("Supply cap reached.".to_string());
         }
 
-         escrow_store.book_tokens(principal, arg.quantity.into());
+        let amount = metadata.booking_cost(currency, self.escrow.total_booked_tokens, quantity, now);
+        let position = self.escrow.position(&investor, currency.ledger, &metadata);
+        // A ledger fee per token booked in this ledger is kept back on top of the price.
+        let total_cost = position.amount + amount + (position.quantity + quantity) * fee;
+        if let Some(escrow_balance) = escrow_balance.filter(|balance| *balance < total_cost) {
+            return Err(format!("Invalid balance in escrow. Req quantity: {quantity} Total invested: {booked} Current balanace: {escrow_balance}, total cost in ledger units: {total_cost}"));
+        }
 
-        Ok(true)
+        Ok(self.escrow.record_booking(investor, currency.ledger, quantity, amount, now))
     }
 
     pub async fn change_ownership(&self, arg0: Principal) -> Result<Nat, String> {
         let canister = self
-            .metadata
-            .clone()
+            .metadata()
             .map(|f| f.metadata.asset_canister)
             .ok_or("Metadata not set".to_string())?;
 
         let current_user = self
-            .metadata
-            .clone()
+            .metadata()
             .map(|f| f.metadata.collection_owner)
             .ok_or("Metadata not set".to_string())?;
 
@@ -255,7 +349,7 @@ impl State {
 
         crate::permissions::revoke_asset_edit_perms(canister, current_user).await?;
 
-        Ok(self.transactions.index().clone())
+        Ok(self.transactions.index())
     }
 
     pub async fn get_booked_tokens(&self, arg0: Option<Principal>) -> u128 {
@@ -275,6 +369,9 @@ impl State {
         if escrow_store.sale_status != SaleStatus::Live {
             return Err("Sale not live.".to_string());
         }
+        if let Some(state) = self.metadata() {
+            state.metadata.check_sale_window(ic_cdk::api::time())?;
+        }
 
         let principal = ic_cdk::api::id();
         let subaccount = Subaccount::from(&ic_cdk::caller());
@@ -295,13 +392,17 @@ impl State {
 
     pub async fn get_metadata(&self) -> Result<GetMetadataRet, String> {
         Ok(self
-            .metadata
-            .as_ref()
+            .metadata()
             .map(|f| f.metadata.with_supply(f.total_supply.into()))
             .clone()
             .ok_or("Init args not set".to_string())?)
     }
 
+    pub fn get_sale_summary(&self) -> Result<SaleSummary, String> {
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
+        Ok(self.escrow.summary(&metadata))
+    }
+
     pub async fn get_participating_investors(&self) -> Vec<Principal> {
         self.escrow.clone().get_participating_investors()
     }
@@ -321,17 +422,18 @@ impl State {
     }
 
     pub fn icrc_7_balance_of(&self, arg0: Vec<Icrc7BalanceOfArgItem>) -> Vec<u64> {
-        arg0.iter()
-            .map(|account| {
-                let account_id = TokenState::to_account_id(
-                    account.owner.to_text().as_str(),
-                    &Some(account.subaccount.clone()),
-                );
-                self.tokens
-                    .owner_to_token_index
-                    .get(&account_id)
-                    .map_or(0, |tokens| tokens.len() as u64)
-            })
+        if let Err(message) = self.settings.check_query_batch(arg0.len()) {
+            ic_cdk::trap(&message);
+        }
+
+        let accounts: Vec<_> = arg0.iter().map(|account| (account.owner, Some(account.subaccount.clone()))).collect();
+        if let Some(Err(message)) = accounts.iter().map(|(_, subaccount)| validations::check_subaccount(subaccount)).find(Result::is_err) {
+            ic_cdk::trap(&message);
+        }
+
+        accounts
+            .iter()
+            .map(|(owner, subaccount)| self.tokens.balance_of(&AccountKey::new(*owner, subaccount)))
             .collect()
     }
 
@@ -365,8 +467,12 @@ impl State {
     //     ic_cdk::call(Principal::anonymous(), "icrc7_name", ()).await
     // }
     pub fn icrc_7_owner_of(&self, arg0: Vec<u32>) -> Vec<Option<Icrc7OwnerOfRetItemInner>> {
+        if let Err(message) = self.settings.check_query_batch(arg0.len()) {
+            ic_cdk::trap(&message);
+        }
+
         arg0.into_iter()
-            .map(|id| self.tokens.tokens.get(&id)) // Get the token by ID
+            .map(|id| self.tokens.get(id)) // Get the token by ID
             .map(|token| {
                 token.map(|token| Icrc7OwnerOfRetItemInner {
                     owner: token.owner.principal.clone(),
@@ -388,42 +494,20 @@ impl State {
         &self,
         arg0: Vec<u32>,
     ) -> Vec<Option<Vec<(String, Icrc7TokenMetadataRetItemInnerItem1)>>> {
+        if let Err(message) = self.settings.check_query_batch(arg0.len()) {
+            ic_cdk::trap(&message);
+        }
+
         arg0.iter()
             .map(|id| {
-                if self.tokens.tokens.contains_key(id) {
-                    Some(vec![]) // MetadataResult is empty for existing tokens
-                } else {
-                    None // Return None for non-existing tokens
-                }
+                self.tokens
+                    .get(*id)
+                    .map(|token| token.metadata.into_iter().collect())
             })
             .collect()
     }
     pub fn icrc_7_tokens(&self, prev: Option<u32>, take: Option<u32>) -> Vec<u32> {
-        let mut tokens: Vec<_> = self.tokens.tokens.keys().cloned().collect();
-        tokens.sort();
-
-        // Convert `prev` to the starting token ID or 0
-        let prev_id = prev.unwrap_or(0);
-
-        // Find the index of the `prev_id` in the sorted token list
-        let prev_index = if prev_id == 0 {
-            -1
-        } else {
-            tokens
-                .iter()
-                .position(|&id| id == prev_id)
-                .map_or(-1, |idx| idx as isize)
-        };
-
-        // Determine the number of tokens to take (default to 5 if `take` is not provided)
-        let take_count = take.unwrap_or(5) as usize;
-
-        // Slice the tokens based on the computed index and take count
-        tokens
-            .into_iter()
-            .skip((prev_index + 1) as usize)
-            .take(take_count)
-            .collect()
+        self.tokens.token_ids(prev, self.settings.take(take))
     }
     pub fn icrc_7_tokens_of(
         &self,
@@ -431,133 +515,901 @@ impl State {
         prev: Option<u32>,
         take: Option<u32>,
     ) -> Vec<u32> {
-        let account_id = TokenState::to_account_id(&account.owner.to_text(), &account.subaccount);
+        if let Err(message) = validations::check_subaccount(&account.subaccount) {
+            ic_cdk::trap(&message);
+        }
+        let account = AccountKey::new(account.owner, &account.subaccount);
+        self.tokens.tokens_of(&account, prev, self.settings.take(take))
+    }
+    pub async fn icrc_7_total_supply(&self) -> CallResult<(candid::Nat,)> {
+        ic_cdk::call(Principal::anonymous(), "icrc7_total_supply", ()).await
+    }
+
+    fn check_memo(&self, memo: &Option<Vec<u8>>) -> Result<(), String> {
+        let max_memo_size = self.settings.max_memo_size as usize;
+        match memo {
+            Some(memo) if memo.len() > max_memo_size => {
+                Err(format!("Memo exceeds the maximum size of {max_memo_size} bytes."))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Subaccounts are compared as `Account`s, so every one has to be 32 bytes long.
+    fn check_subaccounts(subaccounts: &[&Option<Vec<u8>>]) -> Result<(), String> {
+        subaccounts.iter().try_for_each(|subaccount| validations::check_subaccount(subaccount))
+    }
+
+    fn listed_message(token_id: u32) -> String {
+        format!("Token {token_id} is listed for sale; delist it first.")
+    }
 
-        // Get the tokens associated with the account ID
-        let tokens: Vec<u32> = self
+    /// Checks a single transfer against the current state, returning the token's current owner.
+    fn validate_transfer(
+        &self,
+        arg: &Icrc7TransferArgItem,
+        hash: &[u8],
+        now: u64,
+    ) -> Result<Owner, Icrc7TransferRetItemInnerErr> {
+        self.check_memo(&arg.memo)
+            .and_then(|_| Self::check_subaccounts(&[&arg.from_subaccount, &arg.to.subaccount]))
+            .map_err(|message| Icrc7TransferRetItemInnerErr::GenericError { message, error_code: Nat::from(1u8) })?;
+
+        // Retries are answered with the original block before anything else is checked,
+        // since the token has already moved.
+        self.recent_transactions.check(arg.created_at_time, hash, now, &self.settings)?;
+
+        let token = self
             .tokens
-            .owner_to_token_index
-            .get(&account_id)
-            .map_or_else(Vec::new, |set| {
-                let mut vec: Vec<u32> = set.keys().cloned().collect();
-                vec.sort(); // Ensure tokens are sorted
-                vec
+            .get(arg.token_id)
+            .ok_or(Icrc7TransferRetItemInnerErr::NonExistingTokenId)?;
+
+        // Validate token ownership
+        let holder = AccountKey::from(&token.owner);
+        if holder != AccountKey::new(caller(), &arg.from_subaccount) {
+            return Err(Icrc7TransferRetItemInnerErr::Unauthorized);
+        }
+
+        if self.marketplace.is_listed(arg.token_id) {
+            return Err(Icrc7TransferRetItemInnerErr::GenericError {
+                message: Self::listed_message(arg.token_id),
+                error_code: Nat::from(1u8),
             });
+        }
+
+        // Validate recipient: a token cannot be sent to the account that holds it
+        if holder == AccountKey::new(arg.to.owner, &arg.to.subaccount) {
+            return Err(Icrc7TransferRetItemInnerErr::InvalidRecipient);
+        }
 
-        // Convert `prev` to the starting token ID or 0
-        let prev_id = prev.unwrap_or(0);
+        Ok(token.owner)
+    }
 
-        // Find the index of the `prev_id` in the sorted token list
-        let prev_index = if prev_id == 0 {
-            -1
-        } else {
-            tokens
+    fn apply_transfer(&mut self, arg: Icrc7TransferArgItem, from: Owner, hash: Vec<u8>) -> Nat {
+        let block_index = self.transfer_token(
+            arg.token_id,
+            from,
+            Owner {
+                principal: arg.to.owner,
+                subaccount: arg.to.subaccount,
+            },
+            None,
+            arg.memo,
+            arg.created_at_time,
+        );
+        self.recent_transactions.insert(arg.created_at_time, hash, block_index.clone());
+        block_index
+    }
+
+    pub fn icrc_7_transfer(
+        &mut self,
+        args: Vec<Icrc7TransferArgItem>,
+    ) -> Vec<Option<Icrc7TransferRetItemInner>> {
+        if let Err(message) = self.settings.check_update_batch(args.len()) {
+            return vec![Some(Icrc7TransferRetItemInner::Err(Icrc7TransferRetItemInnerErr::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message,
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        self.recent_transactions.prune(now, &self.settings);
+
+        if self.settings.atomic_batch_transfers {
+            return self.icrc_7_transfer_atomic(args, now);
+        }
+
+        args.into_iter()
+            .map(|arg| {
+                let hash = transaction_hash("icrc7_transfer", caller(), &arg);
+                let result = match self.validate_transfer(&arg, &hash, now) {
+                    Ok(from) => Icrc7TransferRetItemInner::Ok(self.apply_transfer(arg, from, hash)),
+                    Err(err) => Icrc7TransferRetItemInner::Err(err),
+                };
+                Some(result)
+            })
+            .collect()
+    }
+
+    /// Validates every item before applying any of them. If one item fails, nothing is
+    /// transferred: that item reports its own error and the others a `GenericBatchError`.
+    fn icrc_7_transfer_atomic(
+        &mut self,
+        args: Vec<Icrc7TransferArgItem>,
+        now: u64,
+    ) -> Vec<Option<Icrc7TransferRetItemInner>> {
+        let mut token_ids = BTreeSet::new();
+        let validated: Vec<_> = args
+            .iter()
+            .map(|arg| {
+                let hash = transaction_hash("icrc7_transfer", caller(), arg);
+                if !token_ids.insert(arg.token_id) {
+                    return Err(Icrc7TransferRetItemInnerErr::GenericError {
+                        message: format!("Token {} appears more than once in the batch.", arg.token_id),
+                        error_code: Nat::from(1u8),
+                    });
+                }
+                self.validate_transfer(arg, &hash, now).map(|from| (from, hash))
+            })
+            .collect();
+
+        if let Some(failed) = validated.iter().position(Result::is_err) {
+            return validated
+                .into_iter()
+                .map(|result| {
+                    let err = result.err().unwrap_or_else(|| Icrc7TransferRetItemInnerErr::GenericBatchError {
+                        message: format!("Batch rejected because item {failed} failed validation."),
+                        error_code: Nat::from(1u8),
+                    });
+                    Some(Icrc7TransferRetItemInner::Err(err))
+                })
+                .collect();
+        }
+
+        args.into_iter()
+            .zip(validated.into_iter().flatten())
+            .map(|(arg, (from, hash))| Some(Icrc7TransferRetItemInner::Ok(self.apply_transfer(arg, from, hash))))
+            .collect()
+    }
+
+    /// Burns tokens on behalf of their holder, e.g. when the vehicle is sold or
+    /// the holder exits through a buyback. Only allowed while redemption is enabled.
+    pub fn icrc_7_burn(&mut self, args: Vec<Icrc7BurnArgItem>) -> Vec<Option<Icrc7BurnRetItemInner>> {
+        if let Err(message) = self.settings.check_update_batch(args.len()) {
+            return vec![Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message,
+            }))];
+        }
+
+        if !self.settings.redemption_enabled {
+            return args
                 .iter()
-                .position(|&id| id == prev_id)
-                .map_or(-1, |idx| idx as isize)
-        };
+                .map(|_| {
+                    Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::GenericBatchError {
+                        message: "Redemption is disabled.".to_string(),
+                        error_code: Nat::from(1u8),
+                    }))
+                })
+                .collect();
+        }
 
-        // Determine the number of tokens to take (default to 5 if `take` is not provided)
-        let take_count = take.unwrap_or(5) as usize;
+        let now = ic_cdk::api::time();
+        self.recent_transactions.prune(now, &self.settings);
 
-        // Slice the tokens based on the computed index and take count
-        tokens
-            .into_iter()
-            .skip((prev_index + 1) as usize)
-            .take(take_count)
+        args.into_iter()
+            .map(|arg| {
+                if let Err(message) = self.check_memo(&arg.memo).and_then(|_| validations::check_subaccount(&arg.from_subaccount)) {
+                    return Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::GenericError {
+                        message,
+                        error_code: Nat::from(1u8),
+                    }));
+                }
+
+                let hash = transaction_hash("icrc7_burn", caller(), &arg);
+                if let Err(err) = self.recent_transactions.check(arg.created_at_time, &hash, now, &self.settings) {
+                    return Some(Icrc7BurnRetItemInner::Err(err.into()));
+                }
+
+                let token = match self.tokens.get(arg.token_id) {
+                    Some(t) => t,
+                    None => return Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::NonExistingTokenId)),
+                };
+
+                if AccountKey::from(&token.owner) != AccountKey::new(caller(), &arg.from_subaccount) {
+                    return Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::Unauthorized));
+                }
+
+                if self.marketplace.is_listed(arg.token_id) {
+                    return Some(Icrc7BurnRetItemInner::Err(Icrc7BurnRetItemInnerErr::GenericError {
+                        message: Self::listed_message(arg.token_id),
+                        error_code: Nat::from(1u8),
+                    }));
+                }
+
+                let block_index = self.burn_token(arg.token_id, arg.memo)?;
+                self.recent_transactions.insert(arg.created_at_time, hash, block_index.clone());
+                Some(Icrc7BurnRetItemInner::Ok(block_index))
+            })
             .collect()
     }
-    pub async fn icrc_7_total_supply(&self) -> CallResult<(candid::Nat,)> {
-        ic_cdk::call(Principal::anonymous(), "icrc7_total_supply", ()).await
+
+    pub fn icrc_37_approve_tokens(&mut self, args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+        if let Err(message) = self.settings.check_update_batch(args.len()) {
+            return vec![Some(ApproveTokenResult::Err(ApproveTokenError::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message,
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        args.into_iter()
+            .map(|arg| {
+                let approval = arg.approval_info;
+                let subaccounts = [&approval.from_subaccount, &approval.spender.subaccount];
+                if let Err(message) = self.check_memo(&approval.memo).and_then(|_| Self::check_subaccounts(&subaccounts)) {
+                    return Some(ApproveTokenResult::Err(ApproveTokenError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+                if let Err(err) = check_window(approval.created_at_time, now, &self.settings) {
+                    return Some(ApproveTokenResult::Err(err.into()));
+                }
+
+                let token = match self.tokens.get(arg.token_id) {
+                    Some(t) => t,
+                    None => return Some(ApproveTokenResult::Err(ApproveTokenError::NonExistingTokenId)),
+                };
+
+                let from = AccountKey::new(caller(), &approval.from_subaccount);
+                if AccountKey::from(&token.owner) != from {
+                    return Some(ApproveTokenResult::Err(ApproveTokenError::Unauthorized));
+                }
+
+                if AccountKey::from(&approval.spender) == from {
+                    return Some(ApproveTokenResult::Err(ApproveTokenError::InvalidSpender));
+                }
+
+                let from = token.owner.clone();
+                if let Err(message) = self.approvals.approve_token(arg.token_id, approval.clone(), now) {
+                    return Some(ApproveTokenResult::Err(ApproveTokenError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+
+                Some(ApproveTokenResult::Ok(self.record(Transaction::Approve {
+                    token_id: Some(arg.token_id),
+                    from,
+                    spender: approval.spender.into(),
+                    expires_at: approval.expires_at,
+                    memo: approval.memo,
+                    created_at_time: Some(approval.created_at_time),
+                })))
+            })
+            .collect()
     }
 
-    fn is_subaccounts_eq(a: &Option<Vec<u8>>, b: &Option<Vec<u8>>) -> bool {
-        let default_subaccount = vec![0; 32]; // Default subaccount is 32 zero bytes
-        let a_str = a.as_ref().unwrap_or(&default_subaccount);
-        let b_str = b.as_ref().unwrap_or(&default_subaccount);
-        a_str == b_str
+    pub fn icrc_37_approve_collection(&mut self, args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>> {
+        if let Err(message) = self.settings.check_update_batch(args.len()) {
+            return vec![Some(ApproveCollectionResult::Err(ApproveCollectionError::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message,
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        args.into_iter()
+            .map(|arg| {
+                let approval = arg.approval_info;
+                let subaccounts = [&approval.from_subaccount, &approval.spender.subaccount];
+                if let Err(message) = self.check_memo(&approval.memo).and_then(|_| Self::check_subaccounts(&subaccounts)) {
+                    return Some(ApproveCollectionResult::Err(ApproveCollectionError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+                if let Err(err) = check_window(approval.created_at_time, now, &self.settings) {
+                    return Some(ApproveCollectionResult::Err(err.into()));
+                }
+
+                if AccountKey::from(&approval.spender) == AccountKey::new(caller(), &approval.from_subaccount) {
+                    return Some(ApproveCollectionResult::Err(ApproveCollectionError::InvalidSpender));
+                }
+
+                let from = Owner {
+                    principal: caller(),
+                    subaccount: approval.from_subaccount.clone(),
+                };
+                if let Err(message) = self.approvals.approve_collection(&from, approval.clone(), now) {
+                    return Some(ApproveCollectionResult::Err(ApproveCollectionError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+
+                Some(ApproveCollectionResult::Ok(self.record(Transaction::Approve {
+                    token_id: None,
+                    from,
+                    spender: approval.spender.into(),
+                    expires_at: approval.expires_at,
+                    memo: approval.memo,
+                    created_at_time: Some(approval.created_at_time),
+                })))
+            })
+            .collect()
     }
 
-    pub fn icrc_7_transfer(
+    pub fn icrc_37_revoke_token_approvals(&mut self, args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<RevokeTokenApprovalResult>> {
+        if args.len() > MAX_REVOKE_APPROVALS {
+            return vec![Some(RevokeTokenApprovalResult::Err(RevokeTokenApprovalError::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message: format!("At most {MAX_REVOKE_APPROVALS} approvals can be revoked at once."),
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        args.into_iter()
+            .map(|arg| {
+                let spender_subaccount = arg.spender.as_ref().and_then(|spender| spender.subaccount.clone());
+                let subaccounts = [&arg.from_subaccount, &spender_subaccount];
+                if let Err(message) = self.check_memo(&arg.memo).and_then(|_| Self::check_subaccounts(&subaccounts)) {
+                    return Some(RevokeTokenApprovalResult::Err(RevokeTokenApprovalError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+                if let Some(Err(err)) = arg.created_at_time.map(|created_at_time| check_window(created_at_time, now, &self.settings)) {
+                    return Some(RevokeTokenApprovalResult::Err(err.into()));
+                }
+
+                let token = match self.tokens.get(arg.token_id) {
+                    Some(t) => t,
+                    None => return Some(RevokeTokenApprovalResult::Err(RevokeTokenApprovalError::NonExistingTokenId)),
+                };
+
+                if AccountKey::from(&token.owner) != AccountKey::new(caller(), &arg.from_subaccount) {
+                    return Some(RevokeTokenApprovalResult::Err(RevokeTokenApprovalError::Unauthorized));
+                }
+
+                let from = token.owner.clone();
+                if !self.approvals.revoke_token(arg.token_id, arg.spender.as_ref()) {
+                    return Some(RevokeTokenApprovalResult::Err(RevokeTokenApprovalError::ApprovalDoesNotExist));
+                }
+
+                Some(RevokeTokenApprovalResult::Ok(self.record(Transaction::Revoke {
+                    token_id: Some(arg.token_id),
+                    from,
+                    spender: arg.spender.map(Owner::from),
+                    memo: arg.memo,
+                    created_at_time: arg.created_at_time,
+                })))
+            })
+            .collect()
+    }
+
+    pub fn icrc_37_revoke_collection_approvals(
         &mut self,
-        args: Vec<Icrc7TransferArgItem>,
-    ) -> Vec<Option<Icrc7TransferRetItemInner>> {
+        args: Vec<RevokeCollectionApprovalArg>,
+    ) -> Vec<Option<RevokeCollectionApprovalResult>> {
+        if args.len() > MAX_REVOKE_APPROVALS {
+            return vec![Some(RevokeCollectionApprovalResult::Err(RevokeCollectionApprovalError::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message: format!("At most {MAX_REVOKE_APPROVALS} approvals can be revoked at once."),
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        args.into_iter()
+            .map(|arg| {
+                let spender_subaccount = arg.spender.as_ref().and_then(|spender| spender.subaccount.clone());
+                let subaccounts = [&arg.from_subaccount, &spender_subaccount];
+                if let Err(message) = self.check_memo(&arg.memo).and_then(|_| Self::check_subaccounts(&subaccounts)) {
+                    return Some(RevokeCollectionApprovalResult::Err(RevokeCollectionApprovalError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+                if let Some(Err(err)) = arg.created_at_time.map(|created_at_time| check_window(created_at_time, now, &self.settings)) {
+                    return Some(RevokeCollectionApprovalResult::Err(err.into()));
+                }
+
+                let from = Owner {
+                    principal: caller(),
+                    subaccount: arg.from_subaccount,
+                };
+                if !self.approvals.revoke_collection(&from, arg.spender.as_ref()) {
+                    return Some(RevokeCollectionApprovalResult::Err(RevokeCollectionApprovalError::ApprovalDoesNotExist));
+                }
+
+                Some(RevokeCollectionApprovalResult::Ok(self.record(Transaction::Revoke {
+                    token_id: None,
+                    from,
+                    spender: arg.spender.map(Owner::from),
+                    memo: arg.memo,
+                    created_at_time: arg.created_at_time,
+                })))
+            })
+            .collect()
+    }
+
+    pub fn icrc_37_is_approved(&self, args: Vec<IsApprovedArg>) -> Vec<bool> {
+        if let Err(message) = self.settings.check_query_batch(args.len()) {
+            ic_cdk::trap(&message);
+        }
+
+        if let Some(Err(message)) = args
+            .iter()
+            .map(|arg| Self::check_subaccounts(&[&arg.from_subaccount, &arg.spender.subaccount]))
+            .find(Result::is_err)
+        {
+            ic_cdk::trap(&message);
+        }
+
+        let now = ic_cdk::api::time();
+        args.iter()
+            .map(|arg| {
+                self.tokens.get(arg.token_id).is_some_and(|token| {
+                    AccountKey::from(&token.owner) == AccountKey::new(token.owner.principal, &arg.from_subaccount)
+                        && self.approvals.is_approved(arg.token_id, &token.owner, &arg.spender, now)
+                })
+            })
+            .collect()
+    }
+
+    pub fn icrc_37_get_token_approvals(
+        &self,
+        token_id: u32,
+        prev: Option<TokenApproval>,
+        take: Option<u32>,
+    ) -> Vec<TokenApproval> {
+        let prev = prev.map(|prev| prev.approval_info.spender);
+        if let Some(Err(message)) = prev.as_ref().map(|prev| validations::check_subaccount(&prev.subaccount)) {
+            ic_cdk::trap(&message);
+        }
+        self.approvals
+            .token_approvals(token_id, prev.as_ref(), take, ic_cdk::api::time())
+            .into_iter()
+            .map(|approval_info| TokenApproval { token_id, approval_info })
+            .collect()
+    }
+
+    pub fn icrc_37_get_collection_approvals(
+        &self,
+        owner: Icrc1Account,
+        prev: Option<ApprovalInfo>,
+        take: Option<u32>,
+    ) -> Vec<ApprovalInfo> {
+        let prev = prev.map(|prev| prev.spender);
+        let prev_subaccount = prev.as_ref().and_then(|prev| prev.subaccount.clone());
+        if let Err(message) = Self::check_subaccounts(&[&owner.subaccount, &prev_subaccount]) {
+            ic_cdk::trap(&message);
+        }
+        self.approvals
+            .collection_approvals(&owner.into(), prev.as_ref(), take, ic_cdk::api::time())
+    }
+
+    pub fn icrc_37_transfer_from(&mut self, args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+        if let Err(message) = self.settings.check_update_batch(args.len()) {
+            return vec![Some(TransferFromResult::Err(TransferFromError::GenericBatchError {
+                error_code: Nat::from(1u8),
+                message,
+            }))];
+        }
+
+        let now = ic_cdk::api::time();
+        self.recent_transactions.prune(now, &self.settings);
+
         args.into_iter()
             .map(|arg| {
-                let token_id = arg.token_id;
+                let subaccounts = [&arg.spender_subaccount, &arg.from.subaccount, &arg.to.subaccount];
+                if let Err(message) = self.check_memo(&arg.memo).and_then(|_| Self::check_subaccounts(&subaccounts)) {
+                    return Some(TransferFromResult::Err(TransferFromError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message,
+                    }));
+                }
+
+                let hash = transaction_hash("icrc37_transfer_from", caller(), &arg);
+                if let Err(err) = self.recent_transactions.check(arg.created_at_time, &hash, now, &self.settings) {
+                    return Some(TransferFromResult::Err(err.into()));
+                }
 
-                let token = match self.tokens.tokens.get(&token_id) {
+                let token = match self.tokens.get(arg.token_id) {
                     Some(t) => t,
-                    None => {
-                        return Some(Icrc7TransferRetItemInner::Err(
-                            Icrc7TransferRetItemInnerErr::NonExistingTokenId,
-                        ))
-                    }
+                    None => return Some(TransferFromResult::Err(TransferFromError::NonExistingTokenId)),
                 };
 
-                // Validate token ownership
-                if token.owner.principal != caller()
-                    || !Self::is_subaccounts_eq(&token.owner.subaccount, &arg.from_subaccount)
-                {
-                    return Some(Icrc7TransferRetItemInner::Err(
-                        Icrc7TransferRetItemInnerErr::Unauthorized,
-                    ));
+                if AccountKey::from(&token.owner) != AccountKey::from(&arg.from) {
+                    return Some(TransferFromResult::Err(TransferFromError::Unauthorized));
                 }
 
-                // Validate recipient
-                if caller() == arg.to.owner
-                    && !Self::is_subaccounts_eq(&token.owner.subaccount, &arg.to.subaccount)
-                {
-                    return Some(Icrc7TransferRetItemInner::Err(
-                        Icrc7TransferRetItemInnerErr::InvalidRecipient,
-                    ));
+                let spender = Icrc1Account {
+                    owner: caller(),
+                    subaccount: arg.spender_subaccount,
+                };
+                if !self.approvals.is_approved(arg.token_id, &token.owner, &spender, now) {
+                    return Some(TransferFromResult::Err(TransferFromError::Unauthorized));
                 }
 
-                self.tokens
-                    .transfer(token_id, arg.to.owner, arg.to.subaccount);
+                if self.marketplace.is_listed(arg.token_id) {
+                    return Some(TransferFromResult::Err(TransferFromError::GenericError {
+                        error_code: Nat::from(1u8),
+                        message: Self::listed_message(arg.token_id),
+                    }));
+                }
+
+                if AccountKey::from(&arg.to) == AccountKey::from(&arg.from) {
+                    return Some(TransferFromResult::Err(TransferFromError::InvalidRecipient));
+                }
 
-                // Return the transaction index as the result
-                Some(Icrc7TransferRetItemInner::Ok(self.tokens.counter))
+                let from = token.owner.clone();
+                let block_index = self.transfer_token(
+                    arg.token_id,
+                    from,
+                    arg.to.into(),
+                    Some(spender.into()),
+                    arg.memo,
+                    arg.created_at_time,
+                );
+                self.recent_transactions.insert(arg.created_at_time, hash, block_index.clone());
+                Some(TransferFromResult::Ok(block_index))
             })
             .collect()
     }
-    pub async fn icrc_7_tx_window(&self) -> CallResult<(Option<candid::Nat>,)> {
-        ic_cdk::call(Principal::anonymous(), "icrc7_tx_window", ()).await
+
+    /// Lists one of the caller's tokens at a fixed `price` in one of the sale's ledgers.
+    pub fn list_token(&mut self, token_id: u32, price: u128, ledger: Principal) -> Result<Listing, String> {
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
+        let ledger = metadata.currency(Some(ledger))?.ledger;
+        let token = self.tokens.get(token_id).ok_or(format!("Token {token_id} does not exist."))?;
+        if token.owner.principal != caller() {
+            return Err("You are not authorized to perform this action.".to_string());
+        }
+
+        let listing = Listing {
+            token_id,
+            seller: Icrc1Account {
+                owner: token.owner.principal,
+                subaccount: token.owner.subaccount,
+            },
+            price,
+            ledger,
+            listed_at: ic_cdk::api::time(),
+        };
+        self.marketplace.list(listing.clone())?;
+        Ok(listing)
+    }
+
+    pub fn delist(&mut self, token_id: u32) -> Result<Listing, String> {
+        match self.marketplace.get(token_id) {
+            Some(listing) if listing.seller.owner == caller() => self.marketplace.delist(token_id),
+            Some(_) => Err("You are not authorized to perform this action.".to_string()),
+            None => Err(format!("Token {token_id} is not listed.")),
+        }
+    }
+
+    /// Pays the listed price from the caller's default account to the seller and moves the
+    /// token to the caller in the same call. The caller approves this canister on the ledger
+    /// for the price plus the fee beforehand. A payment whose call failed stays reserved for
+    /// the caller, who calls `buy` again to retry it. Returns the block index of the token transfer.
+    pub async fn buy(&self, token_id: u32) -> Result<Nat, String> {
+        let buyer = caller();
+        let listing = self.marketplace.get(token_id).ok_or(format!("Token {token_id} is not listed."))?;
+        if listing.seller.owner == buyer {
+            return Err("You cannot buy your own token.".to_string());
+        }
+        let fee = ledger::ledger_info(listing.ledger).await?.fee;
+
+        let (listing, purchase) =
+            STATE.with_borrow_mut(|f| f.marketplace.begin_purchase(token_id, buyer, fee, ic_cdk::api::time()))?;
+        let outcome = marketplace::pay_seller(&listing, &purchase).await;
+
+        STATE.with_borrow_mut(|f| {
+            f.marketplace.end_payment(token_id, &outcome);
+            match outcome {
+                TransferOutcome::Paid(block_index) => f.hand_over(token_id, purchase.buyer, block_index),
+                TransferOutcome::Rejected(e) => Err(e),
+                TransferOutcome::Unknown(e) => Err(format!("{e}; call buy again to retry the same payment.")),
+            }
+        })
+    }
+
+    /// Settles a payment for `token_id` that `buy` sent without learning whether it was made,
+    /// as found on the ledger. A paid one hands the token over to the buyer.
+    pub fn resolve_purchase(&mut self, token_id: u32, block_index: Option<Nat>) -> Result<Purchase, String> {
+        let purchase = self.marketplace.resolve_purchase(token_id, &block_index, ic_cdk::api::time())?;
+        if let Some(block_index) = block_index {
+            self.hand_over(token_id, purchase.buyer, block_index)?;
+        }
+        Ok(purchase)
+    }
+
+    /// Moves a listed token to the buyer who paid for it in `payment_block_index`.
+    fn hand_over(&mut self, token_id: u32, buyer: Principal, payment_block_index: Nat) -> Result<Nat, String> {
+        // Listed tokens cannot be transferred or burned, so the seller still holds it.
+        let token = self.tokens.get(token_id).ok_or(format!(
+            "Paid in block {payment_block_index}, but token {token_id} no longer exists."
+        ))?;
+        Ok(self.transfer_token(
+            token_id,
+            token.owner,
+            Owner {
+                principal: buyer,
+                subaccount: None,
+            },
+            None,
+            None,
+            None,
+        ))
+    }
+
+    /// Takes `quantity` tokens off the caller's booking while the sale is live and refunds
+    /// what that frees, fee reserve included, from their escrow to their refund account.
+    pub async fn cancel_booking(&self, quantity: u128, ledger: Option<Principal>) -> Result<Booking, String> {
+        let investor = caller();
+        let metadata = self.metadata().ok_or("Metadata not set".to_string())?.metadata;
+        let ledger = metadata.currency(ledger)?.ledger;
+        let fee = ledger::ledger_info(ledger).await?.fee;
+
+        let (booking, refund) = STATE.with_borrow_mut(|f| {
+            if f.escrow.sale_status != SaleStatus::Live {
+                return Err("Sale not live.".to_string());
+            }
+            let remaining = f.escrow.booked_tokens_of(&investor).saturating_sub(quantity);
+            let min_tokens = f.compliance.limits_of(&investor).ok().and_then(|limits| limits.min_tokens);
+            if let Some(min) = min_tokens.filter(|min| remaining > 0 && remaining < *min) {
+                return Err(format!("Bookings must add up to at least {min} tokens; cancel all of them instead."));
+            }
+            let booking = f.escrow.cancel_booking(investor, ledger, quantity, &metadata, ic_cdk::api::time())?;
+
+            // The fee kept back for every booked token is released with it.
+            let refund = booking.amount + quantity * fee;
+            let pending = (refund > fee).then(|| f.escrow.begin_refund(investor, &booking, refund - fee, fee, ledger));
+            Ok::<_, String>((booking, pending))
+        })?;
+
+        match refund {
+            Some(refund) => Self::send_booking_refund(booking, refund).await,
+            None => Ok(booking),
+        }
+    }
+
+    /// Sends the refund of a cancelled booking of the caller again, after `cancel_booking` could
+    /// not. Only while the sale is live; after that it is refunded with the excess.
+    pub async fn retry_booking_refund(&self, booking_id: u64) -> Result<Booking, String> {
+        let investor = caller();
+        let booking = self
+            .escrow
+            .bookings_of(&investor)
+            .into_iter()
+            .find(|booking| booking.id == booking_id)
+            .ok_or(format!("Booking {booking_id} not found."))?;
+        let ledger = self
+            .escrow
+            .pending_refunds
+            .get(&booking_id)
+            .map(|refund| refund.ledger)
+            .ok_or(format!("Booking {booking_id} has no refund to send."))?;
+        let fee = ledger::ledger_info(ledger).await?.fee;
+
+        let refund = STATE.with_borrow_mut(|f| {
+            if f.escrow.sale_status != SaleStatus::Live {
+                return Err("Sale not live; what is left in escrow is refunded after the sale.".to_string());
+            }
+            f.escrow.retry_refund(investor, booking_id, fee, ic_cdk::api::time())
+        })?;
+        Self::send_booking_refund(booking, refund).await
     }
+
+    async fn send_booking_refund(booking: Booking, refund: PendingRefund) -> Result<Booking, String> {
+        let args = TransferArg {
+            from_subaccount: Some(Subaccount::from(&refund.investor).0),
+            to: escrow::ledger_account(&refund.to),
+            fee: Some(refund.fee.into()),
+            created_at_time: Some(refund.created_at_time),
+            memo: Some(booking.id.into()),
+            amount: refund.amount.into(),
+        };
+        let outcome = ledger::transfer(refund.ledger, args).await;
+        STATE.with_borrow_mut(|f| f.escrow.end_refund(booking.id, &outcome));
+
+        match outcome {
+            TransferOutcome::Paid(block_index) => Ok(Booking {
+                kind: BookingKind::Cancelled { refund_block_index: Some(block_index) },
+                ..booking
+            }),
+            TransferOutcome::Rejected(e) | TransferOutcome::Unknown(e) => Err(format!(
+                "Booking {} cancelled, but the refund failed and stays in escrow; call retry_booking_refund to send it again: {e}",
+                booking.id
+            )),
+        }
+    }
+
     pub async fn refund_excess_after_sale(
         &self,
         arg0: Principal,
     ) -> Result<bool, String> {
-        self.escrow.refund_from_escrow(&arg0, self.metadata.clone().unwrap().metadata.clone() ).await?;
+        self.check_sale_settling()?;
+        for currency in self.metadata().unwrap().metadata.currencies() {
+            self.refund_excess(&arg0, &currency).await?;
+        }
         Ok(true)
     }
 
-    // Validate collection owner
-    pub async fn reject_sale(&self) -> Result<bool, String> {
-        // validations::check_collection_owner()?;
-
-        // Check if the sale is live
-        if self.escrow.sale_status != SaleStatus::Live {
-            return Result::Err("Sale not live.".to_string());
+    /// Refunds what every participating investor has in escrow beyond their unsettled payments,
+    /// in every accepted ledger, and returns what was recorded for them. A refund that fails does
+    /// not stop the others; the failures are listed in the error.
+    pub async fn refund_all_excess(&self) -> Result<Vec<ExcessRefund>, String> {
+        self.check_sale_settling()?;
+        let currencies = self.metadata().ok_or("Metadata not set".to_string())?.metadata.currencies();
+        excess_refund::lock()?;
+
+        let mut refunds = Vec::new();
+        let mut errors = Vec::new();
+        for investor in self.escrow.get_participating_investors() {
+            for currency in &currencies {
+                if let Err(e) = self.refund_excess(&investor, currency).await {
+                    errors.push(format!("{investor} in {}: {e}", currency.ledger));
+                }
+                refunds.extend(excess_refund::get(&(investor, currency.ledger)));
+            }
         }
 
+        excess_refund::unlock();
+        if !errors.is_empty() {
+            return Err(format!(
+                "{} refunds failed; call refund_all_excess again to retry: {}",
+                errors.len(),
+                errors.join("; ")
+            ));
+        }
+        Ok(refunds)
+    }
 
-        // Process refunds for all booked tokens
-        for (investor_principal, _) in self.escrow.booked_tokens.iter() {
-            match self.escrow.refund_from_escrow(investor_principal, self.metadata.as_ref().unwrap().metadata.clone()).await {
-                Result::Err(err) => return Result::Err(err),
-                _ => {
-                STATE.with_borrow_mut(|f| f.escrow.reject_sale_update_invester_booked_tokens(investor_principal));
+    /// Excess is only known once settlement has fixed what each investor pays, or once the
+    /// sale is rejected and all of it is refunded.
+    fn check_sale_settling(&self) -> Result<(), String> {
+        match STATE.with_borrow(|f| f.escrow.sale_status.clone()) {
+            SaleStatus::Settling | SaleStatus::Accepted | SaleStatus::Rejected => Ok(()),
+            _ => Err("Sale not accepted or rejected.".to_string()),
+        }
+    }
 
+    /// Refunds the investor's escrow in `currency`, keeping back a payment `accept_sale` has yet to send.
+    async fn refund_excess(&self, investor: &Principal, currency: &AcceptedLedger) -> Result<RefundResult, String> {
+        let result = match settlement::get(&(*investor, currency.ledger)) {
+            Some(settlement) if settlement.status == SettlementStatus::Pending => {
+                match ledger::ledger_info(currency.ledger).await {
+                    // A payment that may have been made is sent again with its own fee.
+                    Ok(info) => {
+                        let fee = if settlement.outcome_unknown { settlement.fee } else { info.fee };
+                        self.escrow.refund_from_escrow(investor, currency, settlement.amount + fee).await
+                    }
+                    Err(e) => Err(e),
                 }
             }
+            _ => self.escrow.refund_from_escrow(investor, currency, 0).await,
+        };
+        excess_refund::record(*investor, currency.ledger, result.clone(), ic_cdk::api::time());
+        result
+    }
+
+    /// Splits the distribution account over the current holders and sends the payouts.
+    /// Payouts that fail are queued for `retry_failed_payouts`.
+    pub async fn distribute_revenue(&self) -> Result<DistributionRound, String> {
+        let ledger = self
+            .metadata()
+            .map(|f| f.metadata.token)
+            .ok_or("Metadata not set".to_string())?;
+        let fee = ledger::ledger_info(ledger).await?.fee;
+
+        STATE.with_borrow_mut(|f| f.distribution.lock())?;
+
+        let round = match EscrowStore::icrc1_balance_of(ledger, distribution::distribution_account()).await {
+            Ok(balance) => STATE.with_borrow_mut(|f| {
+                let holders = f.tokens.holders();
+                f.distribution.create_round(ledger, fee, balance, &holders, ic_cdk::api::time())
+            }),
+            Err(e) => Err(e),
+        };
+        let round = match round {
+            Ok(round) => round,
+            Err(e) => {
+                STATE.with_borrow_mut(|f| f.distribution.unlock());
+                return Err(e);
+            }
+        };
+
+        for (index, payout) in round.payouts.iter().enumerate() {
+            let index = index as u32;
+            let result = distribution::send_payout(ledger, round.fee, round.id, index, payout).await;
+            STATE.with_borrow_mut(|f| f.distribution.record_attempt(round.id, index, result));
         }
 
-        // Reject the sale
-        STATE.with_borrow_mut(|F| F.escrow.reject_sale());
+        STATE.with_borrow_mut(|f| {
+            f.distribution.unlock();
+            f.distribution.get_round(round.id).ok_or("Distribution round not found".to_string())
+        })
+    }
+
+    /// Sends every queued payout again and returns the ones that are still failing.
+    pub async fn retry_failed_payouts(&self) -> Result<Vec<QueuedPayout>, String> {
+        let queue = STATE.with_borrow_mut(|f| {
+            f.distribution.lock()?;
+            Ok::<_, String>(f.distribution.retry_queue.clone())
+        })?;
+
+        for (round_id, index) in queue {
+            let Some((ledger, fee, payout)) =
+                STATE.with_borrow_mut(|f| f.distribution.prepare_retry(round_id, index, ic_cdk::api::time()))
+            else {
+                continue;
+            };
+            let result = distribution::send_payout(ledger, fee, round_id, index, &payout).await;
+            STATE.with_borrow_mut(|f| f.distribution.record_attempt(round_id, index, result));
+        }
+
+        Ok(STATE.with_borrow_mut(|f| {
+            f.distribution.unlock();
+            f.distribution.queued()
+        }))
+    }
+
+    /// Accrues what was deposited into the dividend account since the last call
+    /// to every token in existence. Returns the amount accrued.
+    pub async fn accrue_revenue(&self) -> Result<u128, String> {
+        let ledger = self
+            .metadata()
+            .map(|f| f.metadata.token)
+            .ok_or("Metadata not set".to_string())?;
+
+        STATE.with_borrow_mut(|f| f.tokens.dividends.begin_accrual())?;
 
+        match EscrowStore::icrc1_balance_of(ledger, dividends::dividend_account()).await {
+            Ok(balance) => Ok(STATE.with_borrow_mut(|f| {
+                let total_supply = f.tokens.count();
+                f.tokens.dividends.accrue(balance, total_supply)
+            })),
+            Err(e) => {
+                STATE.with_borrow_mut(|f| f.tokens.dividends.cancel_accrual());
+                Err(e)
+            }
+        }
+    }
 
-        Result::Ok(true)
+    /// Sends the caller everything the account with `subaccount` can claim, less the ledger fee.
+    /// A claim whose transfer did not return is sent again unchanged by the next call.
+    pub async fn claim(&self, subaccount: Option<Vec<u8>>) -> Result<Nat, String> {
+        let ledger = self
+            .metadata()
+            .map(|f| f.metadata.token)
+            .ok_or("Metadata not set".to_string())?;
+        validations::check_subaccount(&subaccount)?;
+        let account = AccountKey::new(caller(), &subaccount);
+        let fee = ledger::ledger_info(ledger).await?.fee;
+
+        let claim = STATE.with_borrow_mut(|f| {
+            let balance = f.tokens.balance_of(&account);
+            f.tokens.dividends.begin_claim(&account, balance, fee, ic_cdk::api::time())
+        })?;
+
+        let outcome = dividends::send_claim(ledger, &account, &claim).await;
+        STATE.with_borrow_mut(|f| f.tokens.dividends.end_claim(&account, &outcome));
+        match outcome {
+            TransferOutcome::Paid(block_index) => Ok(block_index),
+            TransferOutcome::Rejected(e) => Err(e),
+            TransferOutcome::Unknown(e) => Err(format!("{e}; call claim again to retry the same transfer.")),
+        }
+    }
+
+    /// Rejects the sale and refunds every investor's escrow. The sale stays rejected if some
+    /// refunds fail; `refund_all_excess` or `refund_excess_after_sale` sends them again.
+    pub async fn reject_sale(&self) -> Result<bool, String> {
+        STATE.with_borrow_mut(|f| f.escrow.reject_sale())?;
+        self.refund_all_excess().await?;
+        Ok(true)
     }
 
 
@@ -565,7 +1417,7 @@ impl State {
     //     &self,
     //     arg0: Principal,
     // ) -> Result<bool, String>{
-    //     self.escrow.refund_from_escrow(&arg0, self.metadata.clone().unwrap().metadata.clone() ).await?;
+    //     self.escrow.refund_from_escrow(&arg0, self.metadata().unwrap().metadata ).await?;
     //     Ok(true)
     // }
 
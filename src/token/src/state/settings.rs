@@ -0,0 +1,120 @@
+use candid::{CandidType, Deserialize};
+
+/// One day, in nanoseconds.
+pub const DEFAULT_TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Two minutes, in nanoseconds.
+pub const DEFAULT_PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+pub const DEFAULT_MAX_MEMO_SIZE: u32 = 32;
+pub const DEFAULT_MAX_QUERY_BATCH_SIZE: u32 = 100;
+pub const DEFAULT_MAX_UPDATE_BATCH_SIZE: u32 = 20;
+pub const DEFAULT_MAX_TAKE_VALUE: u32 = 100;
+pub const DEFAULT_MAX_DEFAULT_TAKE_VALUE: u32 = 10;
+
+/// ICRC-7 parameters that apply to the whole collection.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionSettings {
+    pub tx_window: u64,
+    pub permitted_drift: u64,
+    pub max_memo_size: u32,
+    /// Whether holders may burn their tokens through `icrc7_burn`.
+    pub redemption_enabled: bool,
+    /// Whether `icrc7_transfer` applies a batch all-or-nothing.
+    pub atomic_batch_transfers: bool,
+    pub max_query_batch_size: u32,
+    pub max_update_batch_size: u32,
+    pub max_take_value: u32,
+    /// The page size used when a paginated query has no `take`.
+    pub max_default_take_value: u32,
+}
+
+impl Default for CollectionSettings {
+    fn default() -> Self {
+        Self {
+            tx_window: DEFAULT_TX_WINDOW,
+            permitted_drift: DEFAULT_PERMITTED_DRIFT,
+            max_memo_size: DEFAULT_MAX_MEMO_SIZE,
+            redemption_enabled: false,
+            atomic_batch_transfers: false,
+            max_query_batch_size: DEFAULT_MAX_QUERY_BATCH_SIZE,
+            max_update_batch_size: DEFAULT_MAX_UPDATE_BATCH_SIZE,
+            max_take_value: DEFAULT_MAX_TAKE_VALUE,
+            max_default_take_value: DEFAULT_MAX_DEFAULT_TAKE_VALUE,
+        }
+    }
+}
+
+impl CollectionSettings {
+    /// Applies the fields set in `args`, leaving the settings untouched if the result is invalid.
+    pub fn update(&mut self, args: UpdateCollectionSettingsArgs) -> Result<(), String> {
+        let mut settings = self.clone();
+        if let Some(tx_window) = args.tx_window {
+            settings.tx_window = tx_window;
+        }
+        if let Some(permitted_drift) = args.permitted_drift {
+            settings.permitted_drift = permitted_drift;
+        }
+        if let Some(max_memo_size) = args.max_memo_size {
+            settings.max_memo_size = max_memo_size;
+        }
+        if let Some(redemption_enabled) = args.redemption_enabled {
+            settings.redemption_enabled = redemption_enabled;
+        }
+        if let Some(atomic_batch_transfers) = args.atomic_batch_transfers {
+            settings.atomic_batch_transfers = atomic_batch_transfers;
+        }
+        if let Some(max_query_batch_size) = args.max_query_batch_size {
+            settings.max_query_batch_size = max_query_batch_size;
+        }
+        if let Some(max_update_batch_size) = args.max_update_batch_size {
+            settings.max_update_batch_size = max_update_batch_size;
+        }
+        if let Some(max_take_value) = args.max_take_value {
+            settings.max_take_value = max_take_value;
+        }
+        if let Some(max_default_take_value) = args.max_default_take_value {
+            settings.max_default_take_value = max_default_take_value;
+        }
+
+        if settings.max_query_batch_size == 0 || settings.max_update_batch_size == 0 || settings.max_take_value == 0 {
+            return Err("Batch sizes and max_take_value must be at least 1.".to_string());
+        }
+        if settings.max_default_take_value == 0 || settings.max_default_take_value > settings.max_take_value {
+            return Err("max_default_take_value must be between 1 and max_take_value.".to_string());
+        }
+
+        *self = settings;
+        Ok(())
+    }
+
+    /// The number of items a paginated query returns for the requested `take`.
+    pub fn take(&self, take: Option<u32>) -> usize {
+        take.unwrap_or(self.max_default_take_value).min(self.max_take_value) as usize
+    }
+
+    pub fn check_query_batch(&self, len: usize) -> Result<(), String> {
+        if len > self.max_query_batch_size as usize {
+            return Err(format!("Batch exceeds the maximum of {} items.", self.max_query_batch_size));
+        }
+        Ok(())
+    }
+
+    pub fn check_update_batch(&self, len: usize) -> Result<(), String> {
+        if len > self.max_update_batch_size as usize {
+            return Err(format!("Batch exceeds the maximum of {} items.", self.max_update_batch_size));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateCollectionSettingsArgs {
+    pub tx_window: Option<u64>,
+    pub permitted_drift: Option<u64>,
+    pub max_memo_size: Option<u32>,
+    pub redemption_enabled: Option<bool>,
+    pub atomic_batch_transfers: Option<bool>,
+    pub max_query_batch_size: Option<u32>,
+    pub max_update_batch_size: Option<u32>,
+    pub max_take_value: Option<u32>,
+    pub max_default_take_value: Option<u32>,
+}
@@ -0,0 +1,238 @@
+use std::ops::Bound;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg};
+use serde::Serialize;
+
+use super::ledger::{self, TransferOutcome};
+use super::memory::SETTLEMENTS;
+use super::subaccount::Subaccount;
+
+/// How far `accept_sale` got with one investor.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SettlementStatus {
+    Pending,
+    /// The payment reached the treasury in `block_index`; the tokens are not minted yet.
+    Transferred { block_index: Nat },
+    Minted { block_index: Nat },
+}
+
+/// What one investor pays in one ledger and receives for it when the sale is accepted,
+/// fixed when settlement starts.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct Settlement {
+    pub investor: Principal,
+    pub ledger: Principal,
+    pub quantity: u128,
+    pub amount: u128,
+    pub status: SettlementStatus,
+    /// Sent with every attempt at the payment so the ledger deduplicates retries.
+    pub created_at_time: u64,
+    /// The ledger fee of the last attempt, kept while its outcome is unknown.
+    pub fee: u128,
+    /// An attempt did not return, so the payment may have been made. Only a paid attempt or
+    /// `resolve_settlement` clears it.
+    pub outcome_unknown: bool,
+    pub last_error: Option<String>,
+}
+
+impl Settlement {
+    pub fn new(investor: Principal, ledger: Principal, quantity: u128, amount: u128, now: u64) -> Self {
+        Self {
+            investor,
+            ledger,
+            quantity,
+            amount,
+            status: SettlementStatus::Pending,
+            created_at_time: now,
+            fee: 0,
+            outcome_unknown: false,
+            last_error: None,
+        }
+    }
+
+    pub fn key(&self) -> (Principal, Principal) {
+        (self.investor, self.ledger)
+    }
+
+    pub fn is_minted(&self) -> bool {
+        matches!(self.status, SettlementStatus::Minted { .. })
+    }
+}
+
+pub fn insert(settlement: Settlement) {
+    SETTLEMENTS.with_borrow_mut(|settlements| settlements.insert(settlement.key(), settlement));
+}
+
+pub fn get(key: &(Principal, Principal)) -> Option<Settlement> {
+    SETTLEMENTS.with_borrow(|settlements| settlements.get(key))
+}
+
+/// Up to `take` settlements ordered by investor and ledger, starting after `prev`.
+pub fn settlements(prev: Option<(Principal, Principal)>, take: usize) -> Vec<Settlement> {
+    let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+    SETTLEMENTS.with_borrow(|settlements| {
+        settlements
+            .range((start, Bound::Unbounded))
+            .take(take)
+            .map(|(_, settlement)| settlement)
+            .collect()
+    })
+}
+
+pub fn unsettled() -> Vec<Settlement> {
+    SETTLEMENTS.with_borrow(|settlements| {
+        settlements
+            .iter()
+            .map(|(_, settlement)| settlement)
+            .filter(|settlement| !settlement.is_minted())
+            .collect()
+    })
+}
+
+/// Returns the pending payment to send. A payment that may have been made is sent again
+/// unchanged while the ledger deduplicates it, and after that waits for `resolve_settlement`;
+/// any other one is sent with a fresh `created_at_time` and the current `fee`.
+pub fn prepare_payment(key: &(Principal, Principal), fee: u128, now: u64) -> Result<Option<Settlement>, String> {
+    let Some(mut settlement) = get(key).filter(|settlement| settlement.status == SettlementStatus::Pending) else {
+        return Ok(None);
+    };
+    if settlement.outcome_unknown {
+        if !ledger::is_deduplicated(settlement.created_at_time, now) {
+            return Err("the payment may have been made; it waits for the collection owner to resolve it".to_string());
+        }
+        return Ok(Some(settlement));
+    }
+    if !ledger::is_deduplicated(settlement.created_at_time, now) {
+        settlement.created_at_time = now;
+    }
+    settlement.fee = fee;
+    insert(settlement.clone());
+    Ok(Some(settlement))
+}
+
+/// Records the outcome of a payment attempt. Only a pending settlement changes: an overlapping
+/// attempt that ends after the tokens were minted must not send it back to `Transferred`.
+pub fn record_payment(key: &(Principal, Principal), outcome: &TransferOutcome) {
+    let Some(mut settlement) = get(key).filter(|settlement| settlement.status == SettlementStatus::Pending) else {
+        return;
+    };
+    match outcome {
+        TransferOutcome::Paid(block_index) => {
+            settlement.status = SettlementStatus::Transferred { block_index: block_index.clone() };
+            settlement.outcome_unknown = false;
+            settlement.last_error = None;
+        }
+        TransferOutcome::Rejected(e) => settlement.last_error = Some(e.clone()),
+        TransferOutcome::Unknown(e) => {
+            settlement.outcome_unknown = true;
+            settlement.last_error = Some(e.clone());
+        }
+    }
+    insert(settlement);
+}
+
+/// Settles a payment that may have been made, as found on the ledger: paid in `block_index`,
+/// so the tokens are minted by the next `accept_sale`, or not paid, in which case it is sent
+/// again once the ledger can no longer accept the earlier attempt.
+pub fn resolve_settlement(key: &(Principal, Principal), block_index: Option<Nat>, now: u64) -> Result<Settlement, String> {
+    let mut settlement = get(key)
+        .filter(|settlement| settlement.outcome_unknown && settlement.status == SettlementStatus::Pending)
+        .ok_or("The settlement is not waiting to be resolved.".to_string())?;
+
+    match block_index {
+        Some(block_index) => settlement.status = SettlementStatus::Transferred { block_index },
+        None => ledger::check_expired(settlement.created_at_time, now)?,
+    }
+    settlement.outcome_unknown = false;
+    insert(settlement.clone());
+    Ok(settlement)
+}
+
+/// Moves the investor's escrow in the settlement's ledger to the treasury. The memo is the
+/// investor's principal, and a duplicate reported by the ledger counts as paid.
+pub async fn send_payment(treasury: Principal, settlement: &Settlement) -> TransferOutcome {
+    let args = TransferArg {
+        from_subaccount: Some(Subaccount::from(&settlement.investor).0),
+        to: LedgerAccount {
+            owner: treasury,
+            subaccount: None,
+        },
+        fee: Some(settlement.fee.into()),
+        created_at_time: Some(settlement.created_at_time),
+        memo: Some(Memo::from(settlement.investor.as_slice().to_vec())),
+        amount: settlement.amount.into(),
+    };
+
+    ledger::transfer(settlement.ledger, args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries_keep_the_payment_deduplicated() {
+        let investor = (Principal::from_slice(&[1]), Principal::from_slice(&[10]));
+        insert(Settlement::new(investor.0, investor.1, 2, 200, 1_000));
+
+        let settlement = prepare_payment(&investor, 10, 2_000).unwrap().unwrap();
+        assert_eq!((settlement.created_at_time, settlement.fee), (1_000, 10));
+        record_payment(&investor, &TransferOutcome::Rejected("insufficient funds".to_string()));
+        assert_eq!(get(&investor).unwrap().last_error.as_deref(), Some("insufficient funds"));
+
+        // A refused payment is sent again as a new one once the ledger no longer deduplicates it.
+        let later = 1_000 + ledger::LEDGER_TX_WINDOW + 1;
+        let settlement = prepare_payment(&investor, 20, later).unwrap().unwrap();
+        assert_eq!((settlement.created_at_time, settlement.fee), (later, 20));
+
+        record_payment(&investor, &TransferOutcome::Paid(Nat::from(7u64)));
+        assert!(prepare_payment(&investor, 20, later).unwrap().is_none());
+        assert_eq!(unsettled().len(), 1);
+        assert_eq!(get(&investor).unwrap().status, SettlementStatus::Transferred { block_index: Nat::from(7u64) });
+    }
+
+    #[test]
+    fn test_payments_that_may_have_been_made_wait_to_be_resolved() {
+        let investor = (Principal::from_slice(&[3]), Principal::from_slice(&[10]));
+        insert(Settlement::new(investor.0, investor.1, 2, 200, 1_000));
+
+        prepare_payment(&investor, 10, 1_000).unwrap();
+        record_payment(&investor, &TransferOutcome::Unknown("call failed".to_string()));
+        assert!(resolve_settlement(&investor, None, 2_000).is_err());
+
+        // Retried unchanged within the window, even after a refusal, and parked after it.
+        let retry = prepare_payment(&investor, 20, 2_000).unwrap().unwrap();
+        assert_eq!((retry.created_at_time, retry.fee), (1_000, 10));
+        record_payment(&investor, &TransferOutcome::Rejected("bad fee".to_string()));
+        let later = 1_000 + ledger::LEDGER_TX_WINDOW + 1;
+        assert!(prepare_payment(&investor, 20, later).is_err());
+
+        let expired = later + 2 * ledger::LEDGER_PERMITTED_DRIFT;
+        assert!(!resolve_settlement(&investor, None, expired).unwrap().outcome_unknown);
+        assert_eq!(prepare_payment(&investor, 20, expired).unwrap().unwrap().created_at_time, expired);
+
+        record_payment(&investor, &TransferOutcome::Unknown("call failed".to_string()));
+        let paid = resolve_settlement(&investor, Some(Nat::from(9u64)), expired).unwrap();
+        assert_eq!(paid.status, SettlementStatus::Transferred { block_index: Nat::from(9u64) });
+    }
+
+    #[test]
+    fn test_late_payments_do_not_undo_a_mint() {
+        let investor = (Principal::from_slice(&[2]), Principal::from_slice(&[10]));
+        let minted = SettlementStatus::Minted { block_index: Nat::from(3u64) };
+        insert(Settlement {
+            status: minted.clone(),
+            ..Settlement::new(investor.0, investor.1, 2, 200, 1_000)
+        });
+
+        // A second accept_sale that got past prepare_payment reports the ledger's duplicate.
+        record_payment(&investor, &TransferOutcome::Paid(Nat::from(3u64)));
+        record_payment(&investor, &TransferOutcome::Unknown("call failed".to_string()));
+        let settlement = get(&investor).unwrap();
+        assert_eq!(settlement.status, minted);
+        assert!(settlement.last_error.is_none());
+        assert!(unsettled().is_empty());
+    }
+}
@@ -1,17 +1,37 @@
 
-use candid::{CandidType, Deserialize, };
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Principal};
 
 use super::metadata::Metadata;
 use super::escrow::EscrowStore;
 use super::transactions::TxnIndexStore;
+use super::approvals::ApprovalStore;
+use super::deduplication::RecentTransactions;
+use super::settings::CollectionSettings;
+use super::distribution::DistributionStore;
+use super::compliance::ComplianceStore;
+use super::marketplace::MarketplaceStore;
+use super::ledger::LedgerInfo;
+use super::memory::METADATA;
 use super::TokenState;
 
+/// The heap part of the canister state, written to stable memory on upgrade.
+/// Tokens, bookings, collection metadata and the block log are stable structures
+/// in `memory`; the stores below reach them through their own methods.
 #[derive(CandidType, Deserialize, Default, Clone)]
 pub struct State {
-    pub metadata: Option<MetaDataState>, 
     pub escrow: EscrowStore,
     pub transactions: TxnIndexStore,
     pub tokens: TokenState, 
+    pub approvals: ApprovalStore,
+    pub settings: CollectionSettings,
+    pub recent_transactions: RecentTransactions,
+    pub distribution: DistributionStore,
+    pub compliance: ComplianceStore,
+    pub marketplace: MarketplaceStore,
+    /// Cached by `ledger::ledger_info`, by ledger.
+    pub ledger_info: BTreeMap<Principal, LedgerInfo>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -20,6 +40,26 @@ pub struct MetaDataState {
     pub total_supply: u64
 }
 
+impl State {
+    pub fn metadata(&self) -> Option<MetaDataState> {
+        METADATA.with_borrow(|metadata| metadata.get().clone())
+    }
+
+    pub fn set_metadata(&mut self, metadata: MetaDataState) {
+        METADATA
+            .with_borrow_mut(|cell| cell.set(Some(metadata)))
+            .expect("Failed to store metadata");
+    }
+
+    /// Applies `update` to the collection metadata, if it has been set.
+    pub fn update_metadata_state(&mut self, update: impl FnOnce(&mut MetaDataState)) {
+        if let Some(mut metadata) = self.metadata() {
+            update(&mut metadata);
+            self.set_metadata(metadata);
+        }
+    }
+}
+
 impl MetaDataState {
     pub fn increment_supply(&mut self) {
         self.total_supply += 1;
@@ -21,6 +21,14 @@ fn icrc10_supported_standards() -> Vec<SupportedStandard> {
             name: "ICRC-10".to_string(),
             url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-10".to_string(),
         },
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37".to_string(),
+        },
     ]
 }
 
@@ -31,10 +39,14 @@ mod tests {
     #[test]
     fn test_icrc10_supported_standards() {
         let standards = icrc10_supported_standards();
-        assert_eq!(standards.len(), 2);
+        assert_eq!(standards.len(), 4);
         assert_eq!(standards[0].name, "ICRC-7");
         assert_eq!(standards[0].url, "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7");
         assert_eq!(standards[1].name, "ICRC-10");
         assert_eq!(standards[1].url, "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-10");
+        assert_eq!(standards[2].name, "ICRC-3");
+        assert_eq!(standards[2].url, "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3");
+        assert_eq!(standards[3].name, "ICRC-37");
+        assert_eq!(standards[3].url, "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37");
     }
 }
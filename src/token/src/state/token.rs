@@ -1,118 +1,233 @@
 
 use std::collections::BTreeMap;
+use std::ops::Bound;
 
 use candid::{CandidType, Deserialize, Principal,};
 use serde::Serialize;
-use crate::STATE;
 
+use super::account::Account;
+use super::dividends::Dividends;
+use super::memory::{OWNER_TO_TOKEN_INDEX, TOKENS};
+use super::models::{Icrc1Account, Icrc7TokenMetadataRetItemInnerItem1};
+
+/// Tokens and the owner index live in stable memory (`memory::TOKENS` and
+/// `memory::OWNER_TO_TOKEN_INDEX`); only the id counter and the dividend
+/// accumulator are kept on the heap.
 #[derive(CandidType, Deserialize, Default, Clone)]
 pub struct TokenState {
    pub counter: u32,
-   pub tokens: TokenStoreType,
-   pub owner_to_token_index: OwnerToTokenIndexType,
+   /// Settled here whenever a holder's balance changes.
+   pub dividends: Dividends,
 }
 #[derive(Clone, Serialize, Deserialize, Debug, CandidType)]
 pub struct TokenType {
     pub owner: Owner,
+    pub metadata: TokenMetadata,
 }
 
+/// Per-token ICRC-7 metadata, keyed by metadata name.
+pub type TokenMetadata = BTreeMap<String, Icrc7TokenMetadataRetItemInnerItem1>;
+
 #[derive(Clone, Serialize, Deserialize, Debug, CandidType)]
 pub struct Owner {
     pub principal: Principal,
     pub subaccount: Option<Vec<u8>>,
 }
-type TokenStoreType = BTreeMap<u32, TokenType>;
-type OwnerToTokenIndexType = BTreeMap<String, BTreeMap<u32, bool>>;
 
+impl From<Icrc1Account> for Owner {
+    fn from(account: Icrc1Account) -> Self {
+        Self {
+            principal: account.owner,
+            subaccount: account.subaccount,
+        }
+    }
+}
+
+pub const SERIAL_NUMBER_KEY: &str = "serial_number";
+pub const SHARE_KEY: &str = "share";
+pub const MINTED_AT_KEY: &str = "minted_at";
+pub const PURCHASE_PRICE_KEY: &str = "purchase_price";
+/// The ledger `purchase_price` is counted in.
+pub const PURCHASE_LEDGER_KEY: &str = "purchase_ledger";
+pub const IMAGE_KEY: &str = "icrc7:metadata:uri:image";
+
+/// Keys written at mint time, which the collection owner may not overwrite.
+pub const RESERVED_METADATA_KEYS: [&str; 6] = [
+    SERIAL_NUMBER_KEY,
+    SHARE_KEY,
+    MINTED_AT_KEY,
+    PURCHASE_PRICE_KEY,
+    PURCHASE_LEDGER_KEY,
+    IMAGE_KEY,
+];
 
 impl TokenState {
 
-    // Utility function to derive account ID
-pub fn to_account_id(principal: &str, subaccount: &Option<Vec<u8>>) -> String {
-    // Generate account ID based on principal and optional subaccount
-    match subaccount {
-        Some(sub) => format!("{}-{:?}", principal, sub),
-        None => principal.to_string(),
+    pub fn new() -> Self {
+        Self { counter: 1, ..Default::default() }
     }
-}
 
-    pub fn new() -> Self {
-        Self {
-            counter: 1,
-            tokens: BTreeMap::new(),
-            owner_to_token_index: BTreeMap::new(),
-        }
+    pub fn get(&self, token_id: u32) -> Option<TokenType> {
+        TOKENS.with_borrow(|tokens| tokens.get(&token_id))
+    }
+
+    pub fn contains(&self, token_id: u32) -> bool {
+        TOKENS.with_borrow(|tokens| tokens.contains_key(&token_id))
+    }
+
+    /// Up to `take` token ids in ascending order, starting after `prev`.
+    /// `prev` does not have to exist, so a burned token still works as a cursor.
+    pub fn token_ids(&self, prev: Option<u32>, take: usize) -> Vec<u32> {
+        let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+        TOKENS.with_borrow(|tokens| {
+            tokens
+                .range((start, Bound::Unbounded))
+                .take(take)
+                .map(|(token_id, _)| token_id)
+                .collect()
+        })
     }
 
-    pub fn tokens(&self) -> &TokenStoreType {
-        &self.tokens
+    /// Up to `take` ids of the tokens held by `account` in ascending order, starting after `prev`.
+    pub fn tokens_of(&self, account: &Account, prev: Option<u32>, take: usize) -> Vec<u32> {
+        let start = prev.map_or(Bound::Included((*account, u32::MIN)), |prev| Bound::Excluded((*account, prev)));
+        let end = Bound::Included((*account, u32::MAX));
+        OWNER_TO_TOKEN_INDEX.with_borrow(|index| {
+            index
+                .range((start, end))
+                .take(take)
+                .map(|((_, token_id), _)| token_id)
+                .collect()
+        })
     }
 
-    pub fn owner_to_token_index(&self) -> &OwnerToTokenIndexType {
-        &self.owner_to_token_index
+    /// The number of tokens in existence.
+    pub fn count(&self) -> u64 {
+        TOKENS.with_borrow(|tokens| tokens.len())
     }
 
-    pub fn mint(&mut self, principal: Principal, subaccount: Option<Vec<u8>>) -> u32 {
-        let account_id = Self::to_account_id(&principal.to_text(), &subaccount);
+    /// The number of tokens held by `account`.
+    pub fn balance_of(&self, account: &Account) -> u64 {
+        OWNER_TO_TOKEN_INDEX.with_borrow(|index| index.range((*account, u32::MIN)..=(*account, u32::MAX)).count() as u64)
+    }
+
+    /// Mints a token carrying `metadata`; its serial number is added here.
+    pub fn mint(&mut self, principal: Principal, subaccount: Option<Vec<u8>>, mut metadata: TokenMetadata) -> u32 {
         let token_id = self.counter;
         self.counter += 1;
 
-        let user_token_index = self
-            .owner_to_token_index
-            .entry(account_id.clone())
-            .or_insert_with(BTreeMap::new);
+        let receiver = Account::new(principal, &subaccount);
+        self.dividends.settle(&receiver, self.balance_of(&receiver));
+
+        metadata.insert(
+            SERIAL_NUMBER_KEY.to_string(),
+            Icrc7TokenMetadataRetItemInnerItem1::Nat(token_id.into()),
+        );
 
-        self.tokens.insert(
+        self.insert(
             token_id,
             TokenType {
                 owner: Owner {
                     principal: principal,
                     subaccount: subaccount.clone(),
                 },
+                metadata,
             },
         );
-        user_token_index.insert(token_id, true);
 
         token_id
     }
 
-    pub fn burn(&mut self, token_id: u32) {
-        if let Some(token) = self.tokens.remove(&token_id) {
-            let account_id = Self::to_account_id(&token.owner.principal.to_text(), &token.owner.subaccount);
-
-            if let Some(user_token_index) = self.owner_to_token_index.get_mut(&account_id) {
-                user_token_index.remove(&token_id);
+    /// Stores `token` under `token_id` and indexes it by owner.
+    pub fn insert(&mut self, token_id: u32, token: TokenType) {
+        let account = Account::from(&token.owner);
+        TOKENS.with_borrow_mut(|tokens| tokens.insert(token_id, token));
+        OWNER_TO_TOKEN_INDEX.with_borrow_mut(|index| index.insert((account, token_id), ()));
+    }
 
-                if user_token_index.is_empty() {
-                    self.owner_to_token_index.remove(&account_id);
-                }
+    /// Every current holder with the number of tokens they hold, read from the owner index.
+    pub fn holders(&self) -> BTreeMap<Account, u64> {
+        let mut holders = BTreeMap::new();
+        OWNER_TO_TOKEN_INDEX.with_borrow(|index| {
+            for ((account, _), _) in index.iter() {
+                *holders.entry(account).or_insert(0) += 1;
             }
+        });
+        holders
+    }
+
+    /// Removes the token and returns its last owner.
+    pub fn burn(&mut self, token_id: u32) -> Option<Owner> {
+        let holder = Account::from(&self.get(token_id)?.owner);
+        self.dividends.settle(&holder, self.balance_of(&holder));
+
+        let token = TOKENS.with_borrow_mut(|tokens| tokens.remove(&token_id))?;
+        OWNER_TO_TOKEN_INDEX.with_borrow_mut(|index| index.remove(&(holder, token_id)));
 
-            STATE.with_borrow_mut(|F| {F.metadata.as_mut().map(|f| f.decrement_supply());});
+        Some(token.owner)
+    }
 
+    /// Adds `entries` to the token's metadata, leaving the mint-time keys untouched.
+    pub fn extend_metadata(&mut self, token_id: u32, entries: &[(String, Icrc7TokenMetadataRetItemInnerItem1)]) -> Result<(), String> {
+        if let Some((key, _)) = entries.iter().find(|(key, _)| RESERVED_METADATA_KEYS.contains(&key.as_str())) {
+            return Err(format!("Metadata key {key} is set at mint time and cannot be changed."));
         }
+
+        let mut token = self
+            .get(token_id)
+            .ok_or(format!("Token {token_id} does not exist."))?;
+        token.metadata.extend(entries.iter().cloned());
+        TOKENS.with_borrow_mut(|tokens| tokens.insert(token_id, token));
+        Ok(())
     }
 
+    /// Moves a token to a new owner. Both sides settle their dividends first, so the
+    /// seller keeps what accrued before the transfer.
     pub fn transfer(&mut self, token_id: u32, principal: Principal, subaccount: Option<Vec<u8>>) {
-        if let Some(token) = self.tokens.get_mut(&token_id) {
-            let holder_account_id = Self::to_account_id(&token.owner.principal.to_text(), &token.owner.subaccount);
-            let receiver_account_id = Self::to_account_id(&principal.to_text(), &subaccount);
+        if let Some(mut token) = self.get(token_id) {
+            let holder = Account::from(&token.owner);
+            let receiver = Account::new(principal, &subaccount);
+            self.dividends.settle(&holder, self.balance_of(&holder));
+            self.dividends.settle(&receiver, self.balance_of(&receiver));
 
             token.owner.principal = principal;
             token.owner.subaccount = subaccount.clone();
+            TOKENS.with_borrow_mut(|tokens| tokens.insert(token_id, token));
 
-            if let Some(holder_token_index) = self.owner_to_token_index.get_mut(&holder_account_id) {
-                holder_token_index.remove(&token_id);
-
-                if holder_token_index.is_empty() {
-                    self.owner_to_token_index.remove(&holder_account_id);
-                }
-            }
+            OWNER_TO_TOKEN_INDEX.with_borrow_mut(|index| {
+                index.remove(&(holder, token_id));
+                index.insert((receiver, token_id), ());
+            });
+        }
+    }
+}
 
-            self
-                .owner_to_token_index
-                .entry(receiver_account_id.clone())
-                .or_insert_with(BTreeMap::new).insert(token_id, true);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_start_after_prev() {
+        let mut state = TokenState::new();
+        let holder = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        for i in 0..6 {
+            let owner = if i % 2 == 0 { holder } else { other };
+            state.mint(owner, None, TokenMetadata::new());
         }
+        state.burn(3);
+
+        assert_eq!(state.token_ids(None, 2), vec![1, 2]);
+        assert_eq!(state.token_ids(Some(2), 2), vec![4, 5]);
+        // A burned token still works as a cursor.
+        assert_eq!(state.token_ids(Some(3), 10), vec![4, 5, 6]);
+        assert_eq!(state.token_ids(Some(6), 10), Vec::<u32>::new());
+
+        let holder_account = Account::new(holder, &None);
+        assert_eq!(state.balance_of(&holder_account), 2);
+        assert_eq!(state.tokens_of(&holder_account, None, 1), vec![1]);
+        assert_eq!(state.tokens_of(&holder_account, Some(1), 10), vec![5]);
+        // The default subaccount spelled out is the same holder.
+        assert_eq!(state.balance_of(&Account::new(holder, &Some(vec![0; 32]))), 2);
     }
-}
\ No newline at end of file
+}
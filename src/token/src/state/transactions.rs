@@ -1,27 +1,324 @@
+use std::collections::BTreeMap;
+
 use candid::{Deserialize, CandidType};
 use candid::Nat;
+use ic_certification::{fork, labeled, leaf, HashTree};
+use icrc_ledger_types::icrc::generic_value::{Hash, ICRC3Value};
+use icrc_ledger_types::icrc3::blocks::{BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType};
+use serde_bytes::ByteBuf;
+
+use super::memory::{Block, BLOCKS};
+use super::Owner;
+
+const ICRC7_BLOCK_SCHEMA_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7/ICRC-7.md";
+const ICRC37_BLOCK_SCHEMA_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37/ICRC-37.md";
 
+/// Append-only ICRC-3 block log of every mint, transfer, burn and approval.
+/// Blocks are kept in stable memory (`memory::BLOCKS`).
 #[derive(Default, CandidType, Deserialize, Debug, Clone)]
-pub struct TxnIndexStore {
-    index: Nat,
+pub struct TxnIndexStore {}
+
+/// A token operation, before it is encoded as an ICRC-3 block.
+#[derive(Clone, Debug)]
+pub enum Transaction {
+    Mint {
+        token_id: u32,
+        to: Owner,
+        memo: Option<Vec<u8>>,
+    },
+    Burn {
+        token_id: u32,
+        from: Owner,
+        memo: Option<Vec<u8>>,
+    },
+    Transfer {
+        token_id: u32,
+        from: Owner,
+        to: Owner,
+        spender: Option<Owner>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    },
+    /// A token approval, or a collection approval when `token_id` is `None`.
+    Approve {
+        token_id: Option<u32>,
+        from: Owner,
+        spender: Owner,
+        expires_at: Option<u64>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    },
+    /// Revokes token approvals, or collection approvals when `token_id` is `None`.
+    Revoke {
+        token_id: Option<u32>,
+        from: Owner,
+        spender: Option<Owner>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    },
+}
+
+impl Transaction {
+    pub fn btype(&self) -> &'static str {
+        match self {
+            Transaction::Mint { .. } => "7mint",
+            Transaction::Burn { .. } => "7burn",
+            Transaction::Transfer { spender: None, .. } => "7xfer",
+            Transaction::Transfer { spender: Some(_), .. } => "37xfer",
+            Transaction::Approve { token_id: Some(_), .. } => "37approve",
+            Transaction::Approve { token_id: None, .. } => "37approve_coll",
+            Transaction::Revoke { token_id: Some(_), .. } => "37revoke",
+            Transaction::Revoke { token_id: None, .. } => "37revoke_coll",
+        }
+    }
+
+    fn into_value(self) -> ICRC3Value {
+        let mut tx = TxMap::default();
+        match self {
+            Transaction::Mint { token_id, to, memo } => {
+                tx.tid(Some(token_id)).account("to", Some(&to)).memo(memo);
+            }
+            Transaction::Burn { token_id, from, memo } => {
+                tx.tid(Some(token_id)).account("from", Some(&from)).memo(memo);
+            }
+            Transaction::Transfer { token_id, from, to, spender, memo, created_at_time } => {
+                tx.tid(Some(token_id))
+                    .account("from", Some(&from))
+                    .account("to", Some(&to))
+                    .account("spender", spender.as_ref())
+                    .memo(memo)
+                    .nat("ts", created_at_time);
+            }
+            Transaction::Approve { token_id, from, spender, expires_at, memo, created_at_time } => {
+                tx.tid(token_id)
+                    .account("from", Some(&from))
+                    .account("spender", Some(&spender))
+                    .nat("exp", expires_at)
+                    .memo(memo)
+                    .nat("ts", created_at_time);
+            }
+            Transaction::Revoke { token_id, from, spender, memo, created_at_time } => {
+                tx.tid(token_id)
+                    .account("from", Some(&from))
+                    .account("spender", spender.as_ref())
+                    .memo(memo)
+                    .nat("ts", created_at_time);
+            }
+        }
+        ICRC3Value::Map(tx.0)
+    }
+}
+
+/// Builder for the `tx` field of a block that skips absent optional fields.
+#[derive(Default)]
+struct TxMap(BTreeMap<String, ICRC3Value>);
+
+impl TxMap {
+    fn tid(&mut self, token_id: Option<u32>) -> &mut Self {
+        self.nat("tid", token_id.map(u64::from))
+    }
+
+    fn nat(&mut self, key: &str, value: Option<u64>) -> &mut Self {
+        if let Some(value) = value {
+            self.0.insert(key.to_string(), ICRC3Value::Nat(value.into()));
+        }
+        self
+    }
+
+    fn account(&mut self, key: &str, owner: Option<&Owner>) -> &mut Self {
+        if let Some(owner) = owner {
+            self.0.insert(key.to_string(), account_value(owner));
+        }
+        self
+    }
+
+    fn memo(&mut self, memo: Option<Vec<u8>>) -> &mut Self {
+        if let Some(memo) = memo {
+            self.0.insert("memo".to_string(), ICRC3Value::Blob(ByteBuf::from(memo)));
+        }
+        self
+    }
+}
+
+/// Encodes an account the way ICRC-3 expects: `[principal, subaccount?]`.
+fn account_value(owner: &Owner) -> ICRC3Value {
+    let mut account = vec![ICRC3Value::Blob(ByteBuf::from(owner.principal.as_slice().to_vec()))];
+    if let Some(subaccount) = &owner.subaccount {
+        account.push(ICRC3Value::Blob(ByteBuf::from(subaccount.clone())));
+    }
+    ICRC3Value::Array(account)
 }
 
 impl TxnIndexStore {
     /// Creates a new instance of `TxnIndexStore`.
     pub fn new() -> Self {
-        Self {
-            index: Nat::from(0u64),
+        Self {}
+    }
+
+    /// Gets the current index, i.e. the number of blocks in the log.
+    pub fn index(&self) -> Nat {
+        Nat::from(BLOCKS.with_borrow(|blocks| blocks.len()))
+    }
+
+    /// Appends a block for `transaction` and returns its index.
+    pub fn record(&mut self, transaction: Transaction, timestamp: u64) -> Nat {
+        let mut block = BTreeMap::new();
+        block.insert("btype".to_string(), ICRC3Value::Text(transaction.btype().to_string()));
+        block.insert("ts".to_string(), ICRC3Value::Nat(timestamp.into()));
+        if let Some(phash) = self.last_hash() {
+            block.insert("phash".to_string(), ICRC3Value::Blob(ByteBuf::from(phash.to_vec())));
+        }
+        block.insert("tx".to_string(), transaction.into_value());
+
+        let block_index = BLOCKS
+            .with_borrow_mut(|blocks| blocks.append(&Block(ICRC3Value::Map(block))))
+            .expect("Failed to append block");
+        Nat::from(block_index)
+    }
+
+    fn last_block(&self) -> Option<(u64, ICRC3Value)> {
+        BLOCKS.with_borrow(|blocks| {
+            let last_index = blocks.len().checked_sub(1)?;
+            blocks.get(last_index).map(|block| (last_index, block.0))
+        })
+    }
+
+    fn last_hash(&self) -> Option<Hash> {
+        self.last_block().map(|(_, block)| block.hash())
+    }
+
+    /// Returns at most `max_blocks` blocks over all of `args`; callers page on with `log_length`.
+    pub fn get_blocks(&self, args: Vec<GetBlocksRequest>, max_blocks: u64) -> GetBlocksResult {
+        let mut blocks = Vec::new();
+        let mut remaining = max_blocks;
+        for arg in args {
+            let Ok((start, length)) = arg.as_start_and_length() else {
+                continue;
+            };
+            if remaining == 0 {
+                break;
+            }
+            let length = length.min(remaining);
+            BLOCKS.with_borrow(|log| {
+                let end = start.saturating_add(length).min(log.len());
+                for id in start..end {
+                    if let Some(Block(block)) = log.get(id) {
+                        blocks.push(BlockWithId { id: Nat::from(id), block });
+                    }
+                }
+                remaining -= end.saturating_sub(start);
+            });
+        }
+
+        GetBlocksResult {
+            log_length: self.index(),
+            blocks,
+            archived_blocks: vec![],
         }
     }
 
-    /// Gets the current index.
-    pub fn index(&self) -> &Nat {
-        &self.index
+    /// Hash tree over the tip of the log, as described by ICRC-3.
+    fn hash_tree(&self) -> Option<HashTree> {
+        let (last_index, last_block) = self.last_block()?;
+        let last_hash = last_block.hash();
+
+        let mut encoded_index = vec![];
+        leb128::write::unsigned(&mut encoded_index, last_index).ok()?;
+
+        Some(fork(
+            labeled("last_block_hash", leaf(last_hash.to_vec())),
+            labeled("last_block_index", leaf(encoded_index)),
+        ))
+    }
+
+    /// Sets the canister's certified data to the root of the tip hash tree.
+    pub fn certify(&self) {
+        if let Some(tree) = self.hash_tree() {
+            ic_cdk::api::set_certified_data(&tree.digest());
+        }
     }
 
-    /// Increments the index by 1.
-    pub fn increment(&mut self) {
-        self.index += 1u64;
+    pub fn tip_certificate(&self) -> Option<ICRC3DataCertificate> {
+        let certificate = ic_cdk::api::data_certificate()?;
+        let tree = self.hash_tree()?;
+
+        let mut hash_tree = vec![];
+        ciborium::ser::into_writer(&tree, &mut hash_tree).ok()?;
+
+        Some(ICRC3DataCertificate {
+            certificate: ByteBuf::from(certificate),
+            hash_tree: ByteBuf::from(hash_tree),
+        })
     }
 
+    pub fn supported_block_types() -> Vec<SupportedBlockType> {
+        let icrc7 = ["7mint", "7burn", "7xfer"]
+            .into_iter()
+            .map(|block_type| (block_type, ICRC7_BLOCK_SCHEMA_URL));
+        let icrc37 = ["37approve", "37approve_coll", "37revoke", "37revoke_coll", "37xfer"]
+            .into_iter()
+            .map(|block_type| (block_type, ICRC37_BLOCK_SCHEMA_URL));
+
+        icrc7
+            .chain(icrc37)
+            .map(|(block_type, url)| SupportedBlockType {
+                block_type: block_type.to_string(),
+                url: url.to_string(),
+            })
+            .collect()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn owner(id: u8) -> Owner {
+        Owner {
+            principal: Principal::from_slice(&[id]),
+            subaccount: None,
+        }
+    }
+
+    #[test]
+    fn test_record_links_blocks() {
+        let mut log = TxnIndexStore::new();
+        let first = log.record(Transaction::Mint { token_id: 1, to: owner(1), memo: None }, 10);
+        let second = log.record(
+            Transaction::Transfer { token_id: 1, from: owner(1), to: owner(2), spender: None, memo: Some(vec![7]), created_at_time: None },
+            20,
+        );
+
+        assert_eq!(first, Nat::from(0u64));
+        assert_eq!(second, Nat::from(1u64));
+        assert_eq!(log.index(), Nat::from(2u64));
+
+        let result = log.get_blocks(vec![GetBlocksRequest { start: Nat::from(0u64), length: Nat::from(10u64) }], 100);
+        assert_eq!(result.log_length, Nat::from(2u64));
+        assert_eq!(result.blocks.len(), 2);
+
+        let ICRC3Value::Map(second_block) = &result.blocks[1].block else { panic!("block is not a map") };
+        let first_hash = result.blocks[0].block.clone().hash();
+        assert_eq!(second_block.get("phash"), Some(&ICRC3Value::Blob(ByteBuf::from(first_hash.to_vec()))));
+        assert_eq!(second_block.get("btype"), Some(&ICRC3Value::Text("7xfer".to_string())));
+    }
+
+    #[test]
+    fn test_get_blocks_is_capped_over_all_requests() {
+        let mut log = TxnIndexStore::new();
+        for token_id in 0..5 {
+            log.record(Transaction::Mint { token_id, to: owner(1), memo: None }, 10);
+        }
+        let request = |start: u64, length: u64| GetBlocksRequest { start: Nat::from(start), length: Nat::from(length) };
+
+        let result = log.get_blocks(vec![request(0, u64::MAX)], 3);
+        assert_eq!(result.blocks.len(), 3);
+        assert_eq!(result.log_length, Nat::from(5u64));
+
+        let result = log.get_blocks(vec![request(0, 2), request(3, 10), request(0, 10)], 3);
+        let ids: Vec<_> = result.blocks.iter().map(|block| block.id.clone()).collect();
+        assert_eq!(ids, vec![Nat::from(0u64), Nat::from(1u64), Nat::from(3u64)]);
+    }
 }
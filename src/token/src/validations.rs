@@ -4,12 +4,36 @@ use ic_cdk::caller;
 use crate::STATE;
 
 pub fn check_collection_owner() -> Result<(), String> {
-    STATE.with(|f| match &f.borrow().metadata {
+    STATE.with(|f| match f.borrow().metadata() {
         Some(m) if m.metadata.collection_owner == caller() => Ok(()),
         _ => Err("You are not authorized to perform this action.".to_string()),
     })
 }
+/// The collection owner, or the treasury that deposits revenue.
+pub fn check_collection_owner_or_treasury() -> Result<(), String> {
+    STATE.with(|f| match f.borrow().metadata() {
+        Some(m) if m.metadata.collection_owner == caller() || m.metadata.treasury == caller() => Ok(()),
+        _ => Err("You are not authorized to perform this action.".to_string()),
+    })
+}
+/// The collection owner, or a compliance officer appointed by them.
+pub fn check_collection_owner_or_compliance() -> Result<(), String> {
+    STATE.with(|f| {
+        let f = f.borrow();
+        match f.metadata() {
+            Some(m) if m.metadata.collection_owner == caller() || f.compliance.is_compliance_officer(&caller()) => Ok(()),
+            _ => Err("You are not authorized to perform this action.".to_string()),
+        }
+    })
+}
 pub fn check_not_anonymous() -> Result<(), String> {
     if Principal::anonymous() == caller(){ return  Err("You are not authorized to perform this action.".to_string()) };
     Ok(())
 }
+/// ICRC-1 subaccounts are 32 bytes; `None` stands for the default one.
+pub fn check_subaccount(subaccount: &Option<Vec<u8>>) -> Result<(), String> {
+    match subaccount {
+        Some(subaccount) if subaccount.len() != 32 => Err("Subaccounts must be 32 bytes long.".to_string()),
+        _ => Ok(()),
+    }
+}